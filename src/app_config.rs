@@ -1,10 +1,9 @@
-use clap::{Parser, Subcommand};
+use clap::Parser;
 use serde::{Serialize, Deserialize};
-use std::time::Duration;
 use std::env::var;
-use std::path::Path;
+
+use crate::error::FritzError;
 // use duration_string::DurationString;
-use figment::{Figment, providers::{Serialized, Toml, Env, Format}};
 
 #[derive(Parser, Debug, Serialize, Deserialize, Clone)]
 #[command(version, about, long_about = None)]
@@ -13,6 +12,8 @@ pub struct AppConfig {
     pub package_config_file: String,
     #[arg(short, long)]
     pub cache_file_path: String,
+    #[arg(long)]
+    pub system_cache_file_path: String,
     #[arg(short, long)]
     pub max_cache_age: String,
     #[arg(short, long)]
@@ -27,17 +28,133 @@ pub struct AppConfig {
     pub hm_switch: bool,
     #[arg(short, long)]
     pub switch_base_command: String,
+    #[arg(long)]
+    pub index_source: String,
+    #[arg(long)]
+    pub index_flake_ref: String,
+    #[arg(long)]
+    pub index_json_file: String,
+    #[arg(long)]
+    pub index_flake_input: String,
+    #[arg(long)]
+    pub index_channel: String,
+    #[arg(long)]
+    pub flake_lock_file: String,
+    #[arg(long)]
+    pub system: String,
+    #[arg(long)]
+    pub enrich_metadata: bool,
+    #[arg(long)]
+    pub index_metadata_file: String,
+    #[arg(long)]
+    pub index_programs_db: String,
+    #[arg(long)]
+    pub offline: bool,
+    #[arg(long)]
+    pub background_refresh: bool,
+    #[arg(long)]
+    pub mark_home_manager_packages: bool,
+    /// Other systems to index besides the current one, e.g. `aarch64-darwin`.
+    #[arg(skip)]
+    pub target_systems: Vec<String>,
+    #[arg(skip)]
+    pub sources: Vec<SourceConfig>,
+    /// The config file these options were read from, set by `main`.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub config_file: String,
+}
+
+/// One named package set to index, e.g. `nixpkgs` or `unstable`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct SourceConfig {
+    pub name: String,
+    /// Attribute prefix used in the package config file, e.g. `pkgs` or `unstable`.
+    pub prefix: String,
+    #[serde(default = "default_index_source")]
+    pub index_source: String,
+    #[serde(default)]
+    pub flake_ref: String,
+    #[serde(default)]
+    pub json_file: String,
+    /// Local metadata JSON used by `enrich_metadata` instead of evaluating `meta`.
+    #[serde(default)]
+    pub metadata_file: String,
+    /// `programs.sqlite` mapping executables to packages, as shipped with channels.
+    #[serde(default)]
+    pub programs_db: String,
+    /// Root input of the home-manager flake.lock whose locked revision is indexed instead of `flake_ref`.
+    #[serde(default)]
+    pub flake_input: String,
+    /// Channel indexed by the `nix-env` source, e.g. `nixos-unstable`. All channels if empty.
+    #[serde(default)]
+    pub channel: String,
+}
+
+fn default_index_source() -> String {
+    "nix-search".to_string()
+}
+
+impl AppConfig {
+    /// The configured sources, or a single `nixpkgs` source built from the
+    /// top level `index_*` options if no `[[sources]]` are configured.
+    pub fn index_sources(&self) -> Vec<SourceConfig> {
+        if !self.sources.is_empty() {
+            return self.sources.clone();
+        }
+        vec![SourceConfig {
+            name: "nixpkgs".to_string(),
+            prefix: "pkgs".to_string(),
+            index_source: self.index_source.clone(),
+            flake_ref: self.index_flake_ref.clone(),
+            json_file: self.index_json_file.clone(),
+            metadata_file: self.index_metadata_file.clone(),
+            programs_db: self.index_programs_db.clone(),
+            flake_input: self.index_flake_input.clone(),
+            channel: self.index_channel.clone(),
+        }]
+    }
+}
+
+/// `XDG_CONFIG_HOME`, or `~/.config`. None if neither it nor HOME is set.
+pub fn config_home() -> Option<String> {
+    var("XDG_CONFIG_HOME").or_else(|_| var("HOME").map(|home| format!("{}/.config", home))).ok()
+}
+
+fn cache_home() -> Option<String> {
+    var("XDG_CACHE_HOME").or_else(|_| var("HOME").map(|home| format!("{}/.cache", home))).ok()
+}
+
+/// Where older versions of fritz kept the cache by default.
+pub fn legacy_cache_file_path() -> Option<String> {
+    config_home().map(|config_home| format!("{}/fritz/nixpkgs_cache.msgpack", config_home))
+}
+
+impl AppConfig {
+    /// Paths that default to somewhere in HOME have no default without it,
+    /// so they have to be configured.
+    pub fn check_paths(&self) -> Result<(), FritzError> {
+        for (option, path) in [("package_config_file", &self.package_config_file), ("cache_file_path", &self.cache_file_path)] {
+            if path.is_empty() {
+                return Err(FritzError::Config(format!("{} is not set, and there is no HOME to default it to", option)));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Default for AppConfig {
     fn default() -> AppConfig {
-	let config_home = var("XDG_CONFIG_HOME").or_else(|_| var("HOME").map(|home| format!("{}/.config", home))).unwrap();
-	let package_config_file = format!("{}/home-manager/fritz/packages.nix", config_home);
-	let cache_file_path = format!("{}/fritz/nixpkgs_cache.msgpack", config_home);
+	// left empty if HOME is not set either, see `check_paths`
+	let config_home = config_home();
+	let package_config_file = config_home.as_ref().map(|dir| format!("{}/home-manager/fritz/packages.nix", dir)).unwrap_or_default();
+	let cache_file_path = cache_home().map(|dir| format!("{}/fritz/nixpkgs_cache.msgpack", dir)).unwrap_or_default();
+	let index_json_file = config_home.as_ref().map(|dir| format!("{}/fritz/packages.json", dir)).unwrap_or_default();
 	
         AppConfig {
 	    package_config_file,
             cache_file_path,
+            system_cache_file_path: "".to_string(),
             max_cache_age: "12h".to_string(),
             num_print: 10,
             num_search_results: 10,
@@ -45,6 +162,22 @@ impl Default for AppConfig {
             push_change: false,
             hm_switch: true,
             switch_base_command: "home-manager".to_string(),
+            index_source: "nix-search".to_string(),
+            index_flake_ref: "nixpkgs".to_string(),
+            index_json_file,
+            index_flake_input: "nixpkgs".to_string(),
+            index_channel: "".to_string(),
+            flake_lock_file: "".to_string(),
+            system: "".to_string(),
+            enrich_metadata: false,
+            index_metadata_file: "".to_string(),
+            index_programs_db: "".to_string(),
+            offline: false,
+            background_refresh: true,
+            mark_home_manager_packages: false,
+            target_systems: vec![],
+            sources: vec![],
+            config_file: "".to_string(),
         }
     }
 }
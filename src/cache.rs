@@ -1,83 +1,133 @@
 use serde::{Serialize, Deserialize};
-use rmp_serde;
 use duration_string::DurationString;
-use std::{time::SystemTime, process::exit};
-use std::process::Command;
-use std::path::Path;
-use std::fs::File;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::path::{Path, PathBuf};
+use std::fs::{File, OpenOptions, TryLockError};
 use std::io::Write;
+use std::process::{Command, Stdio};
 use std::fs;
-use log::{info,error};
+use log::{info,warn,error};
 use std::collections::HashMap;
-use crate::app_config::AppConfig;
+use crate::app_config::{AppConfig, SourceConfig, legacy_cache_file_path};
+use crate::index_source::get_index_source;
+use crate::flake_lock::{find_flake_lock, read_locked_input, resolve_flake_ref};
+use crate::platform::get_nix_system;
+use crate::metadata::{PackageMeta, enrich, read_metadata_file};
+use crate::programs::read_programs_db;
+use crate::cache_file::build_cache_file;
+use crate::error::FritzError;
+pub use crate::cache_file::{Cache, CacheRecord};
 use indicatif::ProgressBar;
-use platform_info::{PlatformInfo,PlatformInfoAPI,UNameAPI};
 
-const PB_NUM_STEPS:     u64 = 3;
-const PB_START:         u64 = 1;
-const PB_CACHE_FETCHED: u64 = 2;
-const PB_CACHE_PARSED:  u64 = 3;
+/// Bump whenever `CacheHeader`, `CacheEntry` or the cache file layout change.
+pub const CACHE_SCHEMA_VERSION: u32 = 9;
 
-fn get_platform_string() -> String {
-    let info = PlatformInfo::new().expect("Unable to determine platform");
-    println!("{:?}", info);
-    let current_platform = info.sysname().to_string_lossy().to_lowercase();
-    let current_arch  = info.machine().to_string_lossy().to_lowercase();
-    let platform_string = format!("{}-{}", current_arch, current_platform);
-    match &platform_string[..] {
-        "x86_64-linux-gnu" => "legacyPackages.x86_64-linux".to_string(),
-        "arm64-darwin" => "legacyPackages.aarch64-darwin".to_string(),
-        _ => {
-            error!("unknown platform: {}", current_platform);
-            exit(1);
-        }
-    }
-}
+/// How long to wait before retrying a failed background refresh, doubled
+/// with every further failure up to `MAX_REFRESH_RETRY_DELAY`.
+const REFRESH_RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+const MAX_REFRESH_RETRY_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+const PB_START:            u64 = 1;
+const PB_STEPS_PER_SOURCE: u64 = 2;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CacheEntry {
     pub description: String,
     pub pname: String,
     pub version: String,
+    /// Only filled in by sources that include `meta`, or with `enrich_metadata`.
+    #[serde(default)]
+    pub meta: PackageMeta,
+    /// Indexed systems the package is available on. Empty if only the
+    /// current system was indexed.
+    #[serde(default)]
+    pub systems: Vec<String>,
 }
 
+/// The index of a single named source, keyed by attribute path without the prefix.
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Cache {
-    pub nixpkgs: HashMap<String, CacheEntry>,
+pub struct SourceCache {
+    pub name: String,
+    pub prefix: String,
+    pub packages: HashMap<String, CacheEntry>,
+    /// (program, attribute path) pairs from a programs database.
+    pub programs: Vec<(String, String)>,
+}
+
+/// Where the index of one source came from.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SourceInfo {
+    pub name: String,
+    pub prefix: String,
+    /// e.g. `nix search github:NixOS/nixpkgs/<rev>`
+    pub origin: String,
+    /// Locked revision the index was built from, if it came from a flake.lock.
+    pub revision: Option<String>,
+    pub entry_count: usize,
+    /// Whether packages carry license, unfree and broken metadata.
+    pub metadata: bool,
+    /// The configuration the index was built from, to notice when it changes.
+    pub config: SourceConfig,
 }
 
-impl Cache {
-    pub fn package_iter<'a>(&'a self) -> std::collections::hash_map::Iter::<'a, String, CacheEntry> {
-        self.nixpkgs.iter()
+/// Average number of tokens in each field search scores, used to weigh
+/// matches in long fields less.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct FieldLengths {
+    pub attr_path: f32,
+    pub pname: f32,
+    pub description: f32,
+}
+
+/// Written in front of the cache body, so it can be inspected without
+/// deserializing the whole index. Stored as a map with defaulted fields so
+/// that headers written by other schema versions still decode far enough
+/// to compare `schema_version`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct CacheHeader {
+    pub schema_version: u32,
+    /// Seconds since the unix epoch.
+    pub created: u64,
+    pub platform: String,
+    /// Every indexed system, starting with `platform`.
+    pub systems: Vec<String>,
+    pub entry_count: usize,
+    pub sources: Vec<SourceInfo>,
+    pub field_lengths: FieldLengths,
+}
+
+impl CacheHeader {
+    pub fn age(&self) -> std::time::Duration {
+        let created = UNIX_EPOCH + std::time::Duration::from_secs(self.created);
+        SystemTime::now().duration_since(created).unwrap_or_default()
     }
 }
 
-fn read_cache(cache_file_path: &Path) -> Result<Cache, String> {
-    let nixpkgs_cache = match fs::read(cache_file_path) {
-        Ok(x) => {
-	    let nixpkgs: Cache = rmp_serde::from_slice(&x).unwrap();
-	    Ok(nixpkgs)
-	},
-        Err(err) => {
-            eprintln!("error reading nixpkg cache: {}", err);
-            return Err("Couldn't read cache".to_string());
-        }
-    };
-    nixpkgs_cache
+/// Read just the header of the cache file.
+pub fn read_cache_header(cache_file_path: &Path) -> Result<CacheHeader, String> {
+    let file = File::open(cache_file_path).map_err(|e| format!("error reading nixpkg cache: {}", e))?;
+    let mut reader = std::io::BufReader::new(file);
+    read_header(&mut reader)
 }
 
-fn update_cache(cache_path: &Path, progress_bar: Option<&ProgressBar>) -> Result<Cache, String> {
-    let nixpkgs_json = match get_nixpkgs_json(progress_bar) {
-        Ok(x) => x,
-        Err(e) => {
-            return Err(format!("failed to run nix search: {}", e));
-        }
-    };
+pub fn read_header<R: std::io::Read>(reader: &mut R) -> Result<CacheHeader, String> {
+    let header: CacheHeader = rmp_serde::from_read(reader)
+        .map_err(|e| format!("cache header is unreadable: {}", e))?;
+    if header.schema_version != CACHE_SCHEMA_VERSION {
+        return Err(format!("cache schema version is {}, expected {}", header.schema_version, CACHE_SCHEMA_VERSION));
+    }
+    Ok(header)
+}
+
+fn update_cache(config: &AppConfig, system: &str, cache_path: &Path, progress_bar: Option<&ProgressBar>) -> Result<Cache, FritzError> {
+    let (header, sources) = get_nixpkgs_json(config, system, progress_bar).map_err(FritzError::IndexFetch)?;
     info!("saving cache to file: {:?}", cache_path);
-    // nixpkgs_json
-    let parent_dir = Path::new(cache_path).parent().unwrap();
+    let parent_dir = cache_path.parent().unwrap_or(Path::new("."));
     if !parent_dir.exists() {
-	info!("cache directory '{}' does not exist, creating it.", parent_dir.to_str().unwrap());
+	info!("cache directory '{}' does not exist, creating it.", parent_dir.display());
     }
     match fs::create_dir_all(parent_dir) {
 	    Ok(_) => (),
@@ -85,80 +135,826 @@ fn update_cache(cache_path: &Path, progress_bar: Option<&ProgressBar>) -> Result
 		error!("{:?}", x);
 	    }
     };
-    let mut file = match File::create(&cache_path) {
-        Ok(file) => file,
-        Err(e) => {
-            return Err(format!("failed to write to cache file: {}", e));
+    warn_if_in_git_repo(cache_path, progress_bar);
+    remove_temp_files(parent_dir, cache_path);
+    let bytes = build_cache_file(&header, &sources, &previous_versions(cache_path, &header)).map_err(FritzError::Cache)?;
+    // write next to the cache and rename it into place, so readers (which
+    // may have the old file mapped) never see a partly written cache
+    let temp_path = PathBuf::from(format!("{}.{}.tmp", cache_path.display(), std::process::id()));
+    let written = File::create(&temp_path)
+        .and_then(|mut file| file.write_all(&bytes).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temp_path, cache_path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(FritzError::Cache(format!("failed to write to cache file: {}", e)));
+    }
+    clear_refresh_failure(cache_path);
+    info!("successfully wrote cache file");
+    Cache::open(cache_path).map_err(FritzError::Cache)
+}
+
+/// Remove the temporary files of writers that crashed before renaming them
+/// over the cache. Only called with the refresh lock held, so no other
+/// writer is still using one.
+fn remove_temp_files(dir: &Path, cache_path: &Path) {
+    let Some(cache_name) = cache_path.file_name().and_then(|name| name.to_str()) else {
+        return;
+    };
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let is_temp = name.to_str()
+            .and_then(|name| name.strip_prefix(cache_name)?.strip_prefix('.')?.strip_suffix(".tmp"))
+            .is_some_and(|pid| !pid.is_empty() && pid.chars().all(|c| c.is_ascii_digit()));
+        if is_temp {
+            info!("removing leftover {}", entry.path().display());
+            let _ = fs::remove_file(entry.path());
         }
+    }
+}
+
+/// Versions to remember as the previous ones: those in the cache being
+/// replaced, or its previous ones if it was built from the same revisions.
+fn previous_versions(cache_path: &Path, header: &CacheHeader) -> HashMap<String, String> {
+    let Ok(old) = Cache::open(cache_path) else {
+        return HashMap::new();
     };
-    match file.write(&rmp_serde::to_vec(&nixpkgs_json).unwrap()) {
-        Ok(_) => {
-            info!("successfully wrote cache file");
+    let same_revisions = old.header.sources.iter().all(|source| source.revision.is_some())
+        && old.header.sources.iter().map(|source| &source.revision).eq(header.sources.iter().map(|source| &source.revision));
+    old.package_iter().filter_map(|record| {
+        let version = if same_revisions { record.previous_version } else { Some(record.entry.version) };
+        version.map(|version| (record.key, version))
+    }).collect()
+}
+
+/// Written next to a cache that failed to rebuild, so that searches back off
+/// before starting another background refresh. Removed once a rebuild succeeds.
+#[derive(Debug, Serialize, Deserialize)]
+struct RefreshFailure {
+    /// Failed rebuilds in a row.
+    failures: u32,
+    error: String,
+}
+
+fn refresh_failure_path(cache_path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.refresh-failed", cache_path.display()))
+}
+
+/// Where a background refresh writes its output.
+fn refresh_log_path(cache_path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.refresh.log", cache_path.display()))
+}
+
+/// The last failed rebuild of the cache at `cache_path` and how long ago it
+/// was, if the last rebuild failed.
+fn read_refresh_failure(cache_path: &Path) -> Option<(RefreshFailure, Duration)> {
+    let path = refresh_failure_path(cache_path);
+    let failure = serde_json::from_slice(&fs::read(&path).ok()?)
+        .inspect_err(|e| warn!("ignoring unreadable {}: {}", path.display(), e))
+        .ok()?;
+    let since = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()?.elapsed().unwrap_or_default();
+    Some((failure, since))
+}
+
+fn record_refresh_failure(cache_path: &Path, error: &FritzError) {
+    let failures = read_refresh_failure(cache_path).map_or(0, |(failure, _)| failure.failures) + 1;
+    let failure = RefreshFailure { failures, error: error.to_string() };
+    let path = refresh_failure_path(cache_path);
+    let written = serde_json::to_vec(&failure).map_err(|e| e.to_string())
+        .and_then(|json| fs::write(&path, json).map_err(|e| e.to_string()));
+    if let Err(e) = written {
+        warn!("failed to write {}: {}", path.display(), e);
+    }
+}
+
+fn clear_refresh_failure(cache_path: &Path) {
+    let _ = fs::remove_file(refresh_failure_path(cache_path));
+}
+
+/// Whether to wait longer before retrying after `failures` failed rebuilds,
+/// the last one `since` ago.
+fn backing_off(failures: u32, since: Duration) -> bool {
+    let delay = REFRESH_RETRY_DELAY.saturating_mul(1 << failures.clamp(1, 16).saturating_sub(1));
+    since < delay.min(MAX_REFRESH_RETRY_DELAY)
+}
+
+/// Held while the cache is rebuilt, so that only one refresh runs at a time.
+/// The lock is released when this is dropped or the process exits.
+struct RefreshLock(#[allow(dead_code)] File);
+
+/// Warn about a cache inside a git work tree that does not ignore it, a
+/// cache of tens of megabytes is easily committed by accident.
+fn warn_if_in_git_repo(cache_path: &Path, progress_bar: Option<&ProgressBar>) {
+    let Some(repo) = cache_path.ancestors().skip(1).find(|dir| dir.join(".git").exists()) else {
+        return;
+    };
+    let ignored = Command::new("git").arg("-C").arg(repo).arg("check-ignore").arg("-q").arg(cache_path)
+        .stdout(Stdio::null()).stderr(Stdio::null()).status()
+        .is_ok_and(|status| status.success());
+    if ignored {
+        return;
+    }
+    let message = format!("warning: the cache {} is inside the git repository {}, add it to .gitignore or move it by setting cache_file_path",
+                          cache_path.display(), repo.display());
+    match progress_bar {
+        Some(pb) => pb.suspend(|| eprintln!("{}", message)),
+        None => eprintln!("{}", message),
+    }
+}
+
+/// Move a cache from where older versions of fritz kept it by default to
+/// `cache_file_path`, unless there already is a cache there.
+pub fn migrate_legacy_cache(config: &AppConfig) {
+    if let Some(legacy_path) = legacy_cache_file_path() {
+        move_cache(Path::new(&legacy_path), Path::new(&config.cache_file_path));
+    }
+}
+
+fn move_cache(legacy: &Path, cache_path: &Path) {
+    if legacy == cache_path || !legacy.is_file() || cache_path.exists() {
+        return;
+    }
+    info!("moving cache from {} to {}", legacy.display(), cache_path.display());
+    if let Some(parent_dir) = cache_path.parent() {
+        let _ = fs::create_dir_all(parent_dir);
+    }
+    // rename fails across file systems, e.g. for a separate ~/.cache
+    let moved = fs::rename(legacy, cache_path)
+        .or_else(|_| fs::copy(legacy, cache_path).and_then(|_| fs::remove_file(legacy)));
+    match moved {
+        Ok(()) => { let _ = fs::remove_file(format!("{}.lock", legacy.display())); },
+        Err(e) => warn!("failed to move cache from {} to {}: {}", legacy.display(), cache_path.display(), e),
+    }
+}
+
+fn open_lock_file(cache_path: &Path) -> Result<(File, String), FritzError> {
+    let lock_path = format!("{}.lock", cache_path.display());
+    if let Some(parent_dir) = cache_path.parent() {
+        let _ = fs::create_dir_all(parent_dir);
+    }
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+        .map_err(|e| FritzError::Cache(format!("failed to open {}: {}", lock_path, e)))?;
+    Ok((file, lock_path))
+}
+
+/// Take the refresh lock of the cache at `cache_path`, or None if another
+/// process holds it.
+fn try_lock_refresh(cache_path: &Path) -> Result<Option<RefreshLock>, FritzError> {
+    let (file, lock_path) = open_lock_file(cache_path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(RefreshLock(file))),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => Err(FritzError::Cache(format!("failed to lock {}: {}", lock_path, e))),
+    }
+}
+
+/// Take the refresh lock of the cache at `cache_path`, waiting for other
+/// processes to release it.
+fn lock_refresh(cache_path: &Path) -> Result<RefreshLock, FritzError> {
+    let (file, lock_path) = open_lock_file(cache_path)?;
+    file.lock().map_err(|e| FritzError::Cache(format!("failed to lock {}: {}", lock_path, e)))?;
+    Ok(RefreshLock(file))
+}
+
+/// Rebuild the cache while holding the refresh lock. If another process
+/// rebuilt it while we waited for the lock, use that cache instead.
+fn rebuild_cache(config: &AppConfig, system: &str, cache_path: &Path, progress_bar: &ProgressBar) -> Result<Cache, FritzError> {
+    let previous = read_cache_header(cache_path).ok().map(|header| header.created);
+    let _lock = match try_lock_refresh(cache_path)? {
+        Some(lock) => lock,
+        None => {
+            progress_bar.set_message("waiting for another fritz to refresh the cache");
+            lock_refresh(cache_path)?
+        }
+    };
+    if let Ok(header) = read_cache_header(cache_path) {
+        if Some(header.created) != previous && header.platform == system {
+            if let Ok(cache) = Cache::open(cache_path) {
+                info!("cache was rebuilt by another fritz");
+                return Ok(cache);
+            }
+        }
+    }
+    update_cache(config, system, cache_path, Some(progress_bar))
+}
+
+/// Run `fritz cache refresh` detached from this process, unless a refresh
+/// is already running. With `resolve`, the refresh resolves the flake refs
+/// first and keeps the cache if none of them moved.
+fn spawn_background_refresh(config: &AppConfig, resolve: bool) {
+    match try_lock_refresh(Path::new(&config.cache_file_path)) {
+        Ok(Some(lock)) => drop(lock),
+        Ok(None) => {
+            info!("cache refresh already running");
+            return
         },
         Err(e) => {
-            error!("failed to write to cache file: {}", e);
-            return Err("failed to write to cache file".to_string());
+            warn!("{}, not refreshing cache in the background", e);
+            return
+        }
+    }
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            warn!("unable to find fritz executable: {}, not refreshing cache in the background", e);
+            return
+        }
+    };
+    let log_path = refresh_log_path(Path::new(&config.cache_file_path));
+    let log = match File::create(&log_path).and_then(|log| Ok((log.try_clone()?, log))) {
+        Ok(log) => log,
+        Err(e) => {
+            warn!("failed to create {}: {}, not refreshing cache in the background", log_path.display(), e);
+            return
         }
+    };
+    let mut command = Command::new(exe);
+    command.arg("--config").arg(&config.config_file).arg("cache").arg("refresh")
+        .stdin(Stdio::null()).stdout(log.0).stderr(log.1);
+    if resolve {
+        command.arg("--if-changed");
+    }
+    // keep ctrl-c in the terminal from killing the refresh
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    match command.spawn() {
+        Ok(child) => info!("refreshing cache in the background (pid {})", child.id()),
+        Err(e) => warn!("failed to start background cache refresh: {}", e),
     }
-    Ok(nixpkgs_json)
 }
 
-fn get_nixpkgs_json(progress_bar: Option<&ProgressBar>) -> Result<Cache, String> {
-    if progress_bar.is_some() { progress_bar.unwrap().set_position(PB_START); }
-    if progress_bar.is_some() { progress_bar.unwrap().set_message("fetching nixpkgs index"); }
-    let search_result = Command::new("nix").arg("search").arg("nixpkgs").arg("--json").arg("^").output();
-    let search_output = match search_result {
-        Ok(x) => String::from_utf8(x.stdout).unwrap(),
-        Err(e) => {
-            return Err(format!("Failed to run nix search command: {:?}", e));
+/// The flake.lock to take pinned revisions from: `flake_lock_file` if set,
+/// otherwise the nearest flake.lock above `package_config_file`.
+fn get_flake_lock_path(config: &AppConfig) -> Option<PathBuf> {
+    if !config.flake_lock_file.is_empty() {
+        return Some(PathBuf::from(&config.flake_lock_file));
+    }
+    find_flake_lock(Path::new(&config.package_config_file))
+}
+
+/// Point `source_config` at the revision its `flake_input` is locked to,
+/// or else with `resolve`, at the revision its flake ref resolves to now.
+fn pin_source(source_config: &SourceConfig, flake_lock: Option<&Path>, resolve: bool) -> (SourceConfig, Option<String>) {
+    let mut pinned = source_config.clone();
+    if source_config.index_source != "nix-search" {
+        return (pinned, None);
+    }
+    let locked = match flake_lock {
+        Some(lock_path) if !source_config.flake_input.is_empty() => {
+            read_locked_input(lock_path, &source_config.flake_input)
+                .inspect_err(|e| warn!("{}, not pinning '{}'", e, source_config.name))
+                .ok()
+        },
+        _ => None,
+    };
+    let locked = locked.or_else(|| if resolve {
+        resolve_flake_ref(&source_config.flake_ref)
+            .inspect_err(|e| warn!("failed to resolve {}: {}", source_config.flake_ref, e))
+            .ok()
+    } else {
+        None
+    });
+    match locked {
+        Some(locked) => {
+            pinned.flake_ref = locked.flake_ref;
+            (pinned, Some(locked.rev))
+        },
+        None => {
+            info!("indexing '{}' from {}", source_config.name, source_config.flake_ref);
+            (pinned, None)
+        }
+    }
+}
+
+/// Whether the cache still matches what its sources point to.
+enum Freshness {
+    Current,
+    /// The cache is out of date, and why.
+    Changed(String),
+    /// The sources are configured differently than when the cache was built.
+    Reconfigured,
+    /// Some source has no revision to compare, e.g. `nix-env`.
+    Unknown,
+}
+
+/// Compare the revisions the cache was built from with the ones its sources
+/// point to now. Flake refs not pinned by the flake.lock are only resolved,
+/// with `nix flake metadata`, if `resolve` is set.
+fn check_revisions(config: &AppConfig, header: &CacheHeader, resolve: bool) -> Freshness {
+    let sources = config.index_sources();
+    if sources.len() != header.sources.len()
+        || sources.iter().zip(&header.sources).any(|(source, cached)| *source != cached.config) {
+        return Freshness::Reconfigured;
+    }
+    let flake_lock = get_flake_lock_path(config);
+    let mut freshness = Freshness::Current;
+    for (source_config, cached) in sources.iter().zip(&header.sources) {
+        match (pin_source(source_config, flake_lock.as_deref(), resolve).1, &cached.revision) {
+            (Some(current), Some(revision)) if current == *revision => (),
+            (Some(current), _) => return Freshness::Changed(format!("'{}' moved to {}", source_config.name, current)),
+            (None, _) => freshness = Freshness::Unknown,
+        }
+    }
+    freshness
+}
+
+/// Time since the cache was built or last found to be current, see `mark_checked`.
+fn time_since_checked(cache_path: &Path, header: &CacheHeader) -> std::time::Duration {
+    let since_modified = fs::metadata(cache_path).and_then(|metadata| metadata.modified())
+        .map(|modified| modified.elapsed().unwrap_or_default())
+        .unwrap_or(header.age());
+    since_modified.min(header.age())
+}
+
+/// Record that the cache was found to be current by touching the file.
+fn mark_checked(cache_path: &Path) {
+    let touched = OpenOptions::new().write(true).open(cache_path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(e) = touched {
+        info!("unable to update modification time of cache: {}", e);
+    }
+}
+
+/// The current system followed by the other `target_systems`.
+fn index_systems(config: &AppConfig, system: &str) -> Vec<String> {
+    let mut systems = vec![system.to_string()];
+    for target in &config.target_systems {
+        if !systems.contains(target) {
+            systems.push(target.clone());
+        }
+    }
+    systems
+}
+
+/// Add the packages of `other` indexed for `system` to `packages`.
+fn merge_system(packages: &mut HashMap<String, CacheEntry>, other: HashMap<String, CacheEntry>, system: &str) {
+    for (attr, entry) in other {
+        packages.entry(attr).or_insert(CacheEntry { systems: vec![], ..entry }).systems.push(system.to_string());
+    }
+}
+
+fn get_nixpkgs_json(config: &AppConfig, system: &str, progress_bar: Option<&ProgressBar>) -> Result<(CacheHeader, Vec<SourceCache>), String> {
+    if let Some(pb) = progress_bar { pb.set_position(PB_START); }
+    let flake_lock = get_flake_lock_path(config);
+    let flake_lock = flake_lock.as_deref();
+    let mut source_caches = vec![];
+    let mut source_infos = vec![];
+    for unpinned in &config.index_sources() {
+        let (source_config, revision) = pin_source(unpinned, flake_lock, true);
+        let source = get_index_source(&source_config, system)?;
+        info!("fetching index for '{}' from {}", source_config.name, source.describe());
+        let mut packages = match source.fetch(progress_bar) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("failed to fetch index for '{}' from {}: {}", source_config.name, source.describe(), e));
+            }
+        };
+        let mut metadata = source.has_metadata();
+        if config.enrich_metadata && !metadata {
+            let metas = if source_config.metadata_file.is_empty() {
+                source.fetch_metadata(packages.keys().collect(), progress_bar)
+            } else {
+                read_metadata_file(&source_config.metadata_file)
+            };
+            // metadata is optional, an index without it is still useful
+            match metas {
+                Ok(metas) => {
+                    enrich(&mut packages, metas);
+                    metadata = true;
+                },
+                Err(e) => warn!("failed to read package metadata for '{}': {}", source_config.name, e),
+            }
+        }
+        let programs = if source_config.programs_db.is_empty() {
+            vec![]
+        } else {
+            read_programs_db(&source_config.programs_db, system).unwrap_or_else(|e| {
+                warn!("failed to read programs database for '{}': {}", source_config.name, e);
+                vec![]
+            })
+        };
+        let systems = index_systems(config, system);
+        if systems.len() > 1 {
+            for entry in packages.values_mut() {
+                entry.systems = vec![system.to_string()];
+            }
+            for other_system in &systems[1..] {
+                let other_source = get_index_source(&source_config, other_system)?;
+                info!("fetching index for '{}' on {}", source_config.name, other_system);
+                let other_packages = other_source.fetch(progress_bar).map_err(|e| {
+                    format!("failed to fetch index for '{}' on {}: {}", source_config.name, other_system, e)
+                })?;
+                if other_packages.is_empty() {
+                    warn!("'{}' has no packages for {}", source_config.name, other_system);
+                }
+                merge_system(&mut packages, other_packages, other_system);
+            }
+        }
+        info!("indexed {} packages from '{}'", packages.len(), source_config.name);
+        source_infos.push(SourceInfo {
+            name: source_config.name.clone(),
+            prefix: source_config.prefix.clone(),
+            origin: source.describe(),
+            revision,
+            entry_count: packages.len(),
+            metadata,
+            config: unpinned.clone(),
+        });
+        source_caches.push(SourceCache {
+            name: source_config.name.clone(),
+            prefix: source_config.prefix.clone(),
+            packages,
+            programs,
+        });
+        if let Some(pb) = progress_bar { pb.inc(1); }
+    }
+    let header = CacheHeader {
+        schema_version: CACHE_SCHEMA_VERSION,
+        created: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+        platform: system.to_string(),
+        systems: index_systems(config, system),
+        entry_count: source_infos.iter().map(|s| s.entry_count).sum(),
+        sources: source_infos,
+        // known once the records are built, see `build_cache_file`
+        field_lengths: FieldLengths::default(),
+    };
+    Ok((header, source_caches))
+}
+
+fn get_progress_bar(config: &AppConfig) -> ProgressBar {
+    // every other system takes one more fetch per source
+    let steps_per_source = PB_STEPS_PER_SOURCE + config.target_systems.len() as u64;
+    ProgressBar::new(PB_START + steps_per_source * config.index_sources().len() as u64).with_style(
+	indicatif::ProgressStyle::with_template("[{elapsed_precise}] {bar:40} {pos:>7}/{len:7} {wide_msg}").unwrap())
+}
+
+/// Rebuild the cache regardless of its age.
+pub fn refresh_cache(config: &AppConfig) -> Result<Cache, FritzError> {
+    build_cache(config, Path::new(&config.cache_file_path))
+}
+
+/// Whether resolving the flake refs of the sources with `nix flake metadata`
+/// shows that the cache at `cache_path` is still current.
+fn sources_unchanged(config: &AppConfig, cache_path: &Path, header: &CacheHeader) -> bool {
+    match check_revisions(config, header, true) {
+        Freshness::Current => {
+            info!("sources have not changed, keeping cache");
+            mark_checked(cache_path);
+            clear_refresh_failure(cache_path);
+            true
+        },
+        _ => false,
+    }
+}
+
+/// Rebuild the cache unless it was built for this system from the revisions
+/// the sources resolve to now.
+pub fn refresh_cache_if_changed(config: &AppConfig) -> Result<Cache, FritzError> {
+    let cache_path = Path::new(&config.cache_file_path);
+    if let (Ok(header), Ok(system)) = (read_cache_header(cache_path), get_nix_system(config)) {
+        if header.systems == index_systems(config, &system) && sources_unchanged(config, cache_path, &header) {
+            if let Ok(cache) = Cache::open(cache_path) {
+                return Ok(cache);
+            }
         }
+    }
+    refresh_cache(config)
+}
+
+/// Build a cache at `output`, e.g. a shared one for `system_cache_file_path`.
+pub fn build_cache(config: &AppConfig, output: &Path) -> Result<Cache, FritzError> {
+    if config.offline {
+        return Err(FritzError::Cache("not refreshing the cache in offline mode".to_string()));
+    }
+    let Some(_lock) = try_lock_refresh(output)? else {
+        return Err(FritzError::Cache(format!("another fritz is already building {}", output.display())));
     };
-    if progress_bar.is_some() { progress_bar.unwrap().set_position(PB_CACHE_FETCHED); }
-    if progress_bar.is_some() { progress_bar.unwrap().set_message("parsing nixpkgs"); }
-    info!("completed nix search command");
+    let progress_bar = get_progress_bar(config);
+    let nixpkgs = get_nix_system(config).map_err(FritzError::Config)
+        .and_then(|system| update_cache(config, &system, output, Some(&progress_bar)))
+        .inspect_err(|e| record_refresh_failure(output, e));
+    progress_bar.finish_and_clear();
+    nixpkgs
+}
+
+/// Delete the cache file. Returns false if there was nothing to delete.
+pub fn clear_cache(config: &AppConfig) -> Result<bool, FritzError> {
+    let cache_path = Path::new(&config.cache_file_path);
+    if !cache_path.exists() {
+        return Ok(false);
+    }
+    info!("removing cache file: {}", config.cache_file_path);
+    fs::remove_file(cache_path).map_err(|e| FritzError::Cache(format!("failed to remove {}: {}", config.cache_file_path, e)))?;
+    clear_refresh_failure(cache_path);
+    Ok(true)
+}
+
+/// Rebuild an out of date cache, in the background if enabled. With
+/// `resolve`, the flake refs are resolved first, and the cache is only
+/// rebuilt if one of them moved.
+fn refresh_stale_cache(config: &AppConfig, system: &str, cache_path: &Path, header: &CacheHeader, resolve: bool,
+                       progress_bar: &ProgressBar) -> Result<Cache, FritzError> {
+    if config.background_refresh {
+        // return it as is and update it for next time
+        let failure = read_refresh_failure(cache_path);
+        Cache::open(cache_path).inspect(|_| {
+            let Some((failure, since)) = failure else {
+                info!("refreshing cache in the background");
+                return spawn_background_refresh(config, resolve);
+            };
+            let log_path = refresh_log_path(cache_path);
+            warn_stale_cache(header, &format!("refreshing the package index failed, see {}: {}", log_path.display(), failure.error), progress_bar);
+            if backing_off(failure.failures, since) {
+                info!("refreshing the cache failed {} time(s) in a row, retrying later", failure.failures);
+            } else {
+                info!("retrying to refresh cache in the background");
+                spawn_background_refresh(config, resolve);
+            }
+        }).or_else(|e| {
+            warn!("{}, rebuilding cache", e);
+            rebuild_cache(config, system, cache_path, progress_bar)
+        })
+    } else {
+        if resolve && sources_unchanged(config, cache_path, header) {
+            if let Ok(cache) = Cache::open(cache_path) {
+                return Ok(cache);
+            }
+        }
+        rebuild_cache(config, system, cache_path, progress_bar)
+            .or_else(|e| use_stale_cache(cache_path, header, e, progress_bar))
+    }
+}
+
+/// Fall back to the existing cache after refreshing it failed.
+fn use_stale_cache(cache_path: &Path, header: &CacheHeader, error: FritzError, progress_bar: &ProgressBar) -> Result<Cache, FritzError> {
+    match Cache::open(cache_path) {
+        Ok(cache) => {
+            warn_stale_cache(header, &error.to_string(), progress_bar);
+            Ok(cache)
+        },
+        Err(_) => Err(error),
+    }
+}
 
-    let search_output = search_output.replace(&get_platform_string(), "pkgs");
-    let nixpkgs = serde_json::from_str(&search_output).unwrap();
-    let nixpkgs = Cache { nixpkgs };
-    if progress_bar.is_some() { progress_bar.unwrap().set_position(PB_CACHE_PARSED); }
-    Ok(nixpkgs)
+fn warn_stale_cache(header: &CacheHeader, error: &str, progress_bar: &ProgressBar) {
+    progress_bar.suspend(|| {
+        eprintln!("warning: {}", error);
+        eprintln!("warning: using the package index from {:.1} hours ago", header.age().as_secs_f32() / 3600.0);
+    });
 }
 
-pub fn get_cache(config: &AppConfig) -> Result<Cache, String> {
-    let progress_bar = ProgressBar::new(PB_NUM_STEPS).with_style(
-	indicatif::ProgressStyle::with_template("[{elapsed_precise}] {bar:40} {pos:>7}/{len:7} {wide_msg}").unwrap());
-    let max_cache_age = config.max_cache_age.parse::<DurationString>().unwrap().into();
+/// The shared cache at `system_cache_file_path`, if one is set and current.
+/// It is read-only, so it is never refreshed, and flake refs are not resolved.
+fn open_system_cache(config: &AppConfig, system: &str, max_cache_age: std::time::Duration) -> Option<Cache> {
+    if config.system_cache_file_path.is_empty() {
+        return None;
+    }
+    let cache_path = Path::new(&config.system_cache_file_path);
+    let header = read_cache_header(cache_path)
+        .inspect_err(|e| info!("not using system cache: {}", e))
+        .ok()?;
+    if header.platform != system || header.systems != index_systems(config, system) {
+        info!("not using system cache, it was built for {}", header.systems.join(", "));
+        return None;
+    }
+    match check_revisions(config, &header, false) {
+        Freshness::Changed(reason) => {
+            info!("not using system cache, {}", reason);
+            return None;
+        },
+        Freshness::Reconfigured => {
+            info!("not using system cache, it was built from other sources");
+            return None;
+        },
+        Freshness::Unknown if header.age() > max_cache_age => {
+            info!("not using system cache, it is {:.1} hours old", header.age().as_secs_f32() / 3600.0);
+            return None;
+        },
+        _ => (),
+    }
+    info!("using system cache: {}", config.system_cache_file_path);
+    Cache::open(cache_path)
+        .inspect_err(|e| warn!("not using system cache: {}", e))
+        .ok()
+}
+
+pub fn get_cache(config: &AppConfig) -> Result<Cache, FritzError> {
+    if config.offline {
+        // any readable cache will do, however old or for whichever platform
+        info!("offline mode, reading cache: {}", config.cache_file_path);
+        return Cache::open(Path::new(&config.cache_file_path))
+            .or_else(|e| match config.system_cache_file_path.is_empty() {
+                true => Err(e),
+                false => Cache::open(Path::new(&config.system_cache_file_path)),
+            })
+            .map_err(|e| FritzError::Cache(format!("{}, and offline mode is on", e)));
+    }
+    let system = get_nix_system(config).map_err(FritzError::Config)?;
+    let progress_bar = get_progress_bar(config);
+    let max_cache_age = config.max_cache_age.parse::<DurationString>()
+        .map_err(|e| FritzError::Config(format!("max_cache_age '{}': {}", config.max_cache_age, e)))?
+        .into();
+    if let Some(cache) = open_system_cache(config, &system, max_cache_age) {
+        return Ok(cache);
+    }
     let cache_path_str = &config.cache_file_path;
     info!("attempting to read cache: {}", &cache_path_str);
     let cache_path = Path::new(&cache_path_str);
     let cache_exists = match cache_path.try_exists() {
         Ok(x) => x,
         Err(err) => {
-            eprintln!("error checking whether cache exists: {}", err);
-            return Err(format!("Failed to check path {}", cache_path_str));
+            return Err(FritzError::Cache(format!("failed to check path {}: {}", cache_path_str, err)));
         }
     };
     let nixpkgs = if cache_exists {
         info!("cache exists");
-        // If it's old, update it and return it
-        let cache_metadata = cache_path.metadata().expect("Failed to read metadata from cache");
-        let last_mod_time = cache_metadata.modified().expect("failed to read cache last modified time");
-        let cache_age = SystemTime::now().duration_since(last_mod_time).unwrap();
-        if cache_age > max_cache_age {
-            info!("cache is {:.1} minutes old, updating cache", cache_age.as_secs_f32() / 60.0);
-            update_cache(cache_path, Some(&progress_bar))
-        } else {
-            read_cache(cache_path)
+        match read_cache_header(cache_path) {
+            Err(e) => {
+                // Old or corrupt cache, rebuild it
+                warn!("{}, rebuilding cache", e);
+                rebuild_cache(config, &system, cache_path, &progress_bar)
+            },
+            Ok(header) if header.platform != system => {
+                info!("cache was built for {}, rebuilding cache", header.platform);
+                rebuild_cache(config, &system, cache_path, &progress_bar)
+            },
+            Ok(header) if header.systems != index_systems(config, &system) => {
+                info!("cache was built for {}, rebuilding cache", header.systems.join(", "));
+                rebuild_cache(config, &system, cache_path, &progress_bar)
+            },
+            Ok(header) => {
+                // flake.lock pins are cheap to read and checked every time, other
+                // flake refs are only resolved again, by the refresh, once
+                // `max_cache_age` passed
+                let since_checked = time_since_checked(cache_path, &header);
+                match check_revisions(config, &header, false) {
+                    Freshness::Changed(reason) => {
+                        info!("{}, updating cache", reason);
+                        refresh_stale_cache(config, &system, cache_path, &header, false, &progress_bar)
+                    },
+                    // the index is of other sources, not worth serving while refreshing
+                    Freshness::Reconfigured => {
+                        info!("the configured sources changed, rebuilding cache");
+                        rebuild_cache(config, &system, cache_path, &progress_bar)
+                    },
+                    Freshness::Unknown if since_checked > max_cache_age => {
+                        info!("cache was checked {:.1} minutes ago, updating cache", since_checked.as_secs_f32() / 60.0);
+                        refresh_stale_cache(config, &system, cache_path, &header, true, &progress_bar)
+                    },
+                    // If the cache exists and is up to date, read and return it
+                    Freshness::Current | Freshness::Unknown => {
+                        Cache::open(cache_path).or_else(|e| {
+                            warn!("{}, rebuilding cache", e);
+                            rebuild_cache(config, &system, cache_path, &progress_bar)
+                        })
+                    },
+                }
+            },
         }
-        // If the cache exists and is up to date, read and return it
     } else {
         // If it doesn't exist, create it and return it
         info!("cache does not exist");
-        update_cache(cache_path, Some(&progress_bar))
+        rebuild_cache(config, &system, cache_path, &progress_bar)
     };
-    // If we couldn't read it (but it existed) or we couldn't create it, error.
-    match nixpkgs {
-        Ok(nixpkgs) => { Ok(nixpkgs) },
-        Err(e) => { Err(format!("Could not read cache: '{}'", e)) }
+    progress_bar.finish_and_clear();
+    nixpkgs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_file::build_cache_file;
+    use crate::cache_file::tests::entry;
+
+    const LOCK: &str = r#"{
+      "nodes": {
+        "nixpkgs": { "locked": { "type": "github", "owner": "NixOS", "repo": "nixpkgs", "rev": "bbbb" } },
+        "root": { "inputs": { "nixpkgs": "nixpkgs" } }
+      },
+      "root": "root",
+      "version": 7
+    }"#;
+
+    fn header(config: &AppConfig, revision: Option<&str>) -> CacheHeader {
+        CacheHeader {
+            schema_version: CACHE_SCHEMA_VERSION,
+            created: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            sources: config.index_sources().into_iter().map(|source| SourceInfo {
+                name: source.name.clone(),
+                prefix: source.prefix.clone(),
+                revision: revision.map(str::to_string),
+                config: source,
+                ..Default::default()
+            }).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Write a cache with `pkgs.hello` at `version` whose previous version is 0.9.
+    fn write_cache(path: &Path, header: &CacheHeader, version: &str) {
+        let mut hello = entry("hello", "A program that produces a familiar, friendly greeting");
+        hello.version = version.to_string();
+        let source = SourceCache {
+            name: "nixpkgs".to_string(),
+            prefix: "pkgs".to_string(),
+            packages: HashMap::from([("hello".to_string(), hello)]),
+            programs: vec![],
+        };
+        let previous_versions = HashMap::from([("pkgs.hello".to_string(), "0.9".to_string())]);
+        fs::write(path, build_cache_file(header, &[source], &previous_versions).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn backs_off_doubling_up_to_a_day() {
+        let minutes = |minutes: u64| Duration::from_secs(minutes * 60);
+        assert!(backing_off(1, minutes(9)) && !backing_off(1, minutes(11)));
+        assert!(backing_off(2, minutes(19)) && !backing_off(2, minutes(21)));
+        assert!(backing_off(4, minutes(79)) && !backing_off(4, minutes(81)));
+        assert!(backing_off(30, minutes(23 * 60)) && !backing_off(30, minutes(24 * 60 + 1)));
+    }
+
+    #[test]
+    fn compares_revisions_with_the_flake_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("flake.lock");
+        fs::write(&lock_path, LOCK).unwrap();
+        let config = AppConfig { flake_lock_file: lock_path.display().to_string(), ..Default::default() };
+        assert!(matches!(check_revisions(&config, &header(&config, Some("bbbb")), false), Freshness::Current));
+        assert!(matches!(check_revisions(&config, &header(&config, Some("aaaa")), false), Freshness::Changed(_)));
+        // not pinned by the flake.lock, and not resolved
+        let unpinned = AppConfig { index_flake_input: "".to_string(), ..config.clone() };
+        assert!(matches!(check_revisions(&unpinned, &header(&unpinned, Some("bbbb")), false), Freshness::Unknown));
+        let nix_env = AppConfig { index_source: "nix-env".to_string(), ..config.clone() };
+        assert!(matches!(check_revisions(&nix_env, &header(&nix_env, None), false), Freshness::Unknown));
+        // the cache of a differently configured source is of no use
+        let json_file = AppConfig { index_source: "json-file".to_string(), ..config.clone() };
+        assert!(matches!(check_revisions(&json_file, &header(&config, Some("bbbb")), false), Freshness::Reconfigured));
+    }
+
+    #[test]
+    fn counts_checked_time_from_the_last_touch() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_path = dir.path().join("cache.msgpack");
+        let config = AppConfig::default();
+        let mut old_header = header(&config, None);
+        old_header.created -= 3 * 60 * 60;
+        // without a file, only the age of the cache counts
+        assert!(time_since_checked(&cache_path, &old_header) >= Duration::from_secs(3 * 60 * 60));
+        write_cache(&cache_path, &old_header, "2.12.1");
+        File::options().write(true).open(&cache_path).unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(2 * 60 * 60)).unwrap();
+        let since_checked = time_since_checked(&cache_path, &old_header);
+        assert!(since_checked >= Duration::from_secs(2 * 60 * 60) && since_checked < Duration::from_secs(3 * 60 * 60));
+        mark_checked(&cache_path);
+        assert!(time_since_checked(&cache_path, &old_header) < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn keeps_previous_versions_when_rebuilt_from_the_same_revision() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_path = dir.path().join("cache.msgpack");
+        let config = AppConfig::default();
+        assert!(previous_versions(&cache_path, &header(&config, Some("bbbb"))).is_empty());
+        write_cache(&cache_path, &header(&config, Some("bbbb")), "2.12.1");
+        assert_eq!(previous_versions(&cache_path, &header(&config, Some("bbbb")))["pkgs.hello"], "0.9");
+        assert_eq!(previous_versions(&cache_path, &header(&config, Some("cccc")))["pkgs.hello"], "2.12.1");
+        // without revisions every rebuild is a new one
+        write_cache(&cache_path, &header(&config, None), "2.12.1");
+        assert_eq!(previous_versions(&cache_path, &header(&config, None))["pkgs.hello"], "2.12.1");
+    }
+
+    #[test]
+    fn moves_legacy_cache_unless_one_exists() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = dir.path().join("config/nixpkgs_cache.msgpack");
+        let cache_path = dir.path().join("cache/fritz/nixpkgs_cache.msgpack");
+        fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+        fs::write(&legacy, "legacy").unwrap();
+        fs::write(format!("{}.lock", legacy.display()), "").unwrap();
+        move_cache(&legacy, &cache_path);
+        assert_eq!(fs::read_to_string(&cache_path).unwrap(), "legacy");
+        assert!(!legacy.exists() && !Path::new(&format!("{}.lock", legacy.display())).exists());
+        fs::write(&legacy, "older").unwrap();
+        move_cache(&legacy, &cache_path);
+        assert_eq!(fs::read_to_string(&cache_path).unwrap(), "legacy");
+        assert!(legacy.exists());
+    }
+
+    #[test]
+    fn removes_temp_files_of_crashed_writers() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_path = dir.path().join("cache.msgpack");
+        for name in ["cache.msgpack", "cache.msgpack.1234.tmp", "cache.msgpack.lock", "other.msgpack.99.tmp", "cache.msgpack.x.tmp"] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        remove_temp_files(dir.path(), &cache_path);
+        let mut left: Vec<String> = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["cache.msgpack", "cache.msgpack.lock", "cache.msgpack.x.tmp", "other.msgpack.99.tmp"]);
     }
 }
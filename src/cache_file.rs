@@ -0,0 +1,643 @@
+use serde::{Serialize, Deserialize};
+use memmap2::Mmap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use log::{error, info};
+
+use crate::cache::{CacheEntry, CacheHeader, FieldLengths, SourceCache, read_header};
+use crate::fuzzy::{edit_distance, max_typos};
+
+// On-disk layout, all integers little endian:
+//
+//   header           msgpack map, see `CacheHeader`
+//   tokens           sorted, '\n' terminated lowercase tokens
+//   token offsets    u64 per token + 1, relative to the start of tokens
+//   posting offsets  u64 per token + 1, index into postings
+//   postings         u32 record ids, sorted per token
+//   trigrams         sorted u32 hashes of the trigrams of "^token$"
+//   trigram offsets  u64 per trigram + 1, index into trigram postings
+//   trigram postings u32 token ids, sorted per trigram
+//   record offsets   u64 per record + 1, relative to the start of records
+//   records          msgpack `CacheRecord`s sorted by key
+//   programs         msgpack map of executable name to record ids
+//   footer           section start offsets and counts, then `MAGIC`
+const MAGIC: &[u8; 8] = b"FRITZIDX";
+const FOOTER_FIELDS: usize = 13;
+const FOOTER_LEN: usize = FOOTER_FIELDS * 8 + MAGIC.len();
+
+/// A single package as stored in the cache file.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CacheRecord {
+    /// Full attribute name including the source prefix, e.g. `unstable.neovim`.
+    pub key: String,
+    pub source: String,
+    pub entry: CacheEntry,
+    /// Version in the index this one replaced, if the package was in it.
+    pub previous_version: Option<String>,
+}
+
+impl CacheRecord {
+    /// The key without the source prefix, e.g. `python3Packages.requests`.
+    pub fn attr_path(&self) -> &str {
+        self.key.split_once('.').map_or(&self.key[..], |(_, attr)| attr)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    tokens: usize,
+    token_offsets: usize,
+    posting_offsets: usize,
+    postings: usize,
+    trigrams: usize,
+    trigram_offsets: usize,
+    trigram_postings: usize,
+    record_offsets: usize,
+    records: usize,
+    programs: usize,
+    num_tokens: usize,
+    num_trigrams: usize,
+    num_records: usize,
+}
+
+/// Split text into lowercase alphanumeric tokens.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+}
+
+/// Hashes of the trigrams of `text`, with `^` and `$` marking the start and
+/// end of a token if `padded`. Tokens are alphanumeric, so the markers never
+/// clash with their characters.
+fn trigrams(text: &str, padded: bool) -> Vec<u32> {
+    let chars: Vec<char> = if padded {
+        std::iter::once('^').chain(text.chars()).chain(std::iter::once('$')).collect()
+    } else {
+        text.chars().collect()
+    };
+    let mut hashes: Vec<u32> = chars.windows(3).map(|trigram| {
+        // FNV-1a, collisions only add candidates that are then checked
+        trigram.iter().collect::<String>().bytes()
+            .fold(0x811c9dc5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+    }).collect();
+    hashes.sort_unstable();
+    hashes.dedup();
+    hashes
+}
+
+fn read_u64(data: &[u8], pos: usize) -> Option<usize> {
+    let bytes = data.get(pos..pos + 8)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?) as usize)
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    let bytes = data.get(pos..pos + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+impl Layout {
+    fn read(data: &[u8]) -> Result<Layout, String> {
+        if data.len() < FOOTER_LEN || &data[data.len() - MAGIC.len()..] != MAGIC {
+            return Err("cache has no index footer".to_string());
+        }
+        let footer = data.len() - FOOTER_LEN;
+        let field = |i: usize| read_u64(data, footer + i * 8).unwrap_or(usize::MAX);
+        let layout = Layout {
+            tokens: field(0),
+            token_offsets: field(1),
+            posting_offsets: field(2),
+            postings: field(3),
+            trigrams: field(4),
+            trigram_offsets: field(5),
+            trigram_postings: field(6),
+            record_offsets: field(7),
+            records: field(8),
+            programs: field(9),
+            num_tokens: field(10),
+            num_trigrams: field(11),
+            num_records: field(12),
+        };
+        let table_len = |count: usize| count.checked_add(1).and_then(|n| n.checked_mul(8));
+        let sections = [layout.tokens, layout.token_offsets, layout.posting_offsets, layout.postings, layout.trigrams,
+                        layout.trigram_offsets, layout.trigram_postings, layout.record_offsets, layout.records, layout.programs, footer];
+        if sections.windows(2).any(|w| w[0] > w[1])
+            || Some(layout.posting_offsets - layout.token_offsets) != table_len(layout.num_tokens)
+            || Some(layout.postings - layout.posting_offsets) != table_len(layout.num_tokens)
+            || layout.num_trigrams.checked_mul(4) != Some(layout.trigram_offsets - layout.trigrams)
+            || Some(layout.trigram_postings - layout.trigram_offsets) != table_len(layout.num_trigrams)
+            || Some(layout.records - layout.record_offsets) != table_len(layout.num_records) {
+            return Err("cache index footer is corrupt".to_string());
+        }
+        // only the ends of the offset tables are checked here, so that opening
+        // the cache does not touch every page of it; entries are bounds checked
+        // as they are read
+        let table_spans = |table: usize, count: usize, len: usize| {
+            read_u64(data, table) == Some(0) && read_u64(data, table + count * 8) == Some(len)
+        };
+        let postings_len = layout.trigrams - layout.postings;
+        let trigram_postings_len = layout.record_offsets - layout.trigram_postings;
+        if !postings_len.is_multiple_of(4)
+            || !trigram_postings_len.is_multiple_of(4)
+            || !table_spans(layout.token_offsets, layout.num_tokens, layout.token_offsets - layout.tokens)
+            || !table_spans(layout.posting_offsets, layout.num_tokens, postings_len / 4)
+            || !table_spans(layout.trigram_offsets, layout.num_trigrams, trigram_postings_len / 4)
+            || !table_spans(layout.record_offsets, layout.num_records, layout.programs - layout.records) {
+            return Err("cache index is corrupt".to_string());
+        }
+        Ok(layout)
+    }
+}
+
+/// Serialize the header and the fetched sources into the cache file format.
+/// `previous_versions` maps full keys to the versions to remember as previous.
+pub fn build_cache_file(header: &CacheHeader, sources: &[SourceCache], previous_versions: &HashMap<String, String>) -> Result<Vec<u8>, String> {
+    let mut records: Vec<CacheRecord> = sources.iter().flat_map(|source| {
+        source.packages.iter().map(|(attr, entry)| {
+            let key = format!("{}.{}", source.prefix, attr);
+            CacheRecord {
+                previous_version: previous_versions.get(&key).cloned(),
+                key,
+                source: source.name.clone(),
+                entry: entry.clone(),
+            }
+        })
+    }).collect();
+    // stable sort, so the first source wins if two share a prefix
+    records.sort_by(|a, b| a.key.cmp(&b.key));
+    records.dedup_by(|a, b| a.key == b.key);
+
+    // executables from `mainProgram` and from the sources' programs databases
+    let mut programs: BTreeMap<&str, BTreeSet<u32>> = BTreeMap::new();
+    let ids: HashMap<&str, u32> = records.iter().enumerate().map(|(id, record)| (&record.key[..], id as u32)).collect();
+    for (id, record) in records.iter().enumerate() {
+        if let Some(program) = &record.entry.meta.main_program {
+            programs.entry(program).or_default().insert(id as u32);
+        }
+    }
+    for source in sources {
+        for (program, attr) in &source.programs {
+            if let Some(id) = ids.get(&format!("{}.{}", source.prefix, attr)[..]) {
+                programs.entry(program).or_default().insert(*id);
+            }
+        }
+    }
+
+    let mut tokens: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    for (id, record) in records.iter().enumerate() {
+        let text = [&record.key[..], &record.entry.pname, &record.entry.description];
+        for token in text.iter().flat_map(|t| tokenize(t)) {
+            let postings = tokens.entry(token).or_default();
+            if postings.last() != Some(&(id as u32)) {
+                postings.push(id as u32);
+            }
+        }
+    }
+    let mut trigram_postings: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+    for (token_id, token) in tokens.keys().enumerate() {
+        for trigram in trigrams(token, true) {
+            trigram_postings.entry(trigram).or_default().push(token_id as u32);
+        }
+    }
+
+    let num_records = records.len().max(1) as f32;
+    let average_length = |field: fn(&CacheRecord) -> &str| {
+        records.iter().map(|record| tokenize(field(record)).count()).sum::<usize>() as f32 / num_records
+    };
+    let header = CacheHeader {
+        field_lengths: FieldLengths {
+            attr_path: average_length(|record| record.attr_path()),
+            pname: average_length(|record| &record.entry.pname),
+            description: average_length(|record| &record.entry.description),
+        },
+        ..header.clone()
+    };
+
+    // header as a map so that other schema versions can read its version field
+    let mut out = rmp_serde::to_vec_named(&header).map_err(|e| format!("failed to serialize cache header: {}", e))?;
+    let mut layout = Layout {
+        tokens: out.len(), token_offsets: 0, posting_offsets: 0, postings: 0, trigrams: 0, trigram_offsets: 0,
+        trigram_postings: 0, record_offsets: 0, records: 0, programs: 0,
+        num_tokens: tokens.len(), num_trigrams: trigram_postings.len(), num_records: records.len(),
+    };
+    for token in tokens.keys() {
+        out.extend_from_slice(token.as_bytes());
+        out.push(b'\n');
+    }
+    layout.token_offsets = out.len();
+    let mut token_offset: u64 = 0;
+    out.extend_from_slice(&token_offset.to_le_bytes());
+    for token in tokens.keys() {
+        token_offset += token.len() as u64 + 1;
+        out.extend_from_slice(&token_offset.to_le_bytes());
+    }
+    layout.posting_offsets = out.len();
+    let mut posting_offset: u64 = 0;
+    out.extend_from_slice(&posting_offset.to_le_bytes());
+    for postings in tokens.values() {
+        posting_offset += postings.len() as u64;
+        out.extend_from_slice(&posting_offset.to_le_bytes());
+    }
+    layout.postings = out.len();
+    for id in tokens.values().flatten() {
+        out.extend_from_slice(&id.to_le_bytes());
+    }
+    layout.trigrams = out.len();
+    for trigram in trigram_postings.keys() {
+        out.extend_from_slice(&trigram.to_le_bytes());
+    }
+    layout.trigram_offsets = out.len();
+    let mut trigram_offset: u64 = 0;
+    out.extend_from_slice(&trigram_offset.to_le_bytes());
+    for token_ids in trigram_postings.values() {
+        trigram_offset += token_ids.len() as u64;
+        out.extend_from_slice(&trigram_offset.to_le_bytes());
+    }
+    layout.trigram_postings = out.len();
+    for token_id in trigram_postings.values().flatten() {
+        out.extend_from_slice(&token_id.to_le_bytes());
+    }
+    let encoded_records = records.iter()
+        .map(rmp_serde::to_vec)
+        .collect::<Result<Vec<Vec<u8>>, _>>()
+        .map_err(|e| format!("failed to serialize cache: {}", e))?;
+    layout.record_offsets = out.len();
+    let mut record_offset: u64 = 0;
+    out.extend_from_slice(&record_offset.to_le_bytes());
+    for record in &encoded_records {
+        record_offset += record.len() as u64;
+        out.extend_from_slice(&record_offset.to_le_bytes());
+    }
+    layout.records = out.len();
+    for record in &encoded_records {
+        out.extend_from_slice(record);
+    }
+    layout.programs = out.len();
+    rmp_serde::encode::write(&mut out, &programs).map_err(|e| format!("failed to serialize programs: {}", e))?;
+    for field in [layout.tokens, layout.token_offsets, layout.posting_offsets, layout.postings, layout.trigrams,
+                  layout.trigram_offsets, layout.trigram_postings, layout.record_offsets, layout.records,
+                  layout.programs, layout.num_tokens, layout.num_trigrams, layout.num_records] {
+        out.extend_from_slice(&(field as u64).to_le_bytes());
+    }
+    out.extend_from_slice(MAGIC);
+    Ok(out)
+}
+
+/// A memory-mapped cache file. Records are only decoded when looked up.
+pub struct Cache {
+    data: Mmap,
+    pub header: CacheHeader,
+    layout: Layout,
+    path: PathBuf,
+    file: File,
+}
+
+impl Cache {
+    pub fn open(cache_file_path: &Path) -> Result<Cache, String> {
+        let file = File::open(cache_file_path).map_err(|e| format!("error reading nixpkg cache: {}", e))?;
+        // SAFETY: the mapping is read-only and cache files are never modified
+        // in place: `update_cache` writes a new file and renames it over the
+        // old one, so a mapped file stays intact until it is unmapped.
+        let data = unsafe { Mmap::map(&file) }.map_err(|e| format!("error mapping nixpkg cache: {}", e))?;
+        let header = read_header(&mut &data[..])?;
+        let layout = Layout::read(&data)?;
+        Ok(Cache { data, header, layout, path: cache_file_path.to_path_buf(), file })
+    }
+
+    /// Remove a corrupt cache file so that the next run rebuilds it, unless
+    /// it was replaced by a new one already.
+    fn discard(&self) {
+        let replaced = match (fs::metadata(&self.path), self.file.metadata()) {
+            (Ok(current), Ok(mapped)) => current.dev() != mapped.dev() || current.ino() != mapped.ino(),
+            _ => true,
+        };
+        if replaced {
+            return;
+        }
+        match fs::remove_file(&self.path) {
+            Ok(()) => error!("removed {}, it will be rebuilt on next use", self.path.display()),
+            Err(e) => info!("unable to remove corrupt cache {}: {}", self.path.display(), e),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.layout.num_records
+    }
+
+    /// Decode the record with the given id.
+    pub fn record(&self, id: usize) -> Option<CacheRecord> {
+        if id >= self.layout.num_records {
+            return None;
+        }
+        let start = read_u64(&self.data, self.layout.record_offsets + id * 8)?;
+        let end = read_u64(&self.data, self.layout.record_offsets + (id + 1) * 8)?;
+        let bytes = self.data.get(self.layout.records.saturating_add(start)..self.layout.records.saturating_add(end))?;
+        match rmp_serde::from_slice(bytes) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                error!("cache record {} is corrupt: {}", id, e);
+                self.discard();
+                None
+            }
+        }
+    }
+
+    /// Iterate over all packages, sorted by key.
+    pub fn package_iter(&self) -> impl Iterator<Item = CacheRecord> + '_ {
+        (0..self.len()).filter_map(|id| self.record(id))
+    }
+
+    /// Look up a package by its full key, e.g. `unstable.neovim`.
+    pub fn get(&self, full_key: &str) -> Option<CacheRecord> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            let record = self.record(mid)?;
+            match record.key[..].cmp(full_key) {
+                std::cmp::Ordering::Equal => return Some(record),
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+            }
+        }
+        None
+    }
+
+    /// Look up a package by its full key, or by a name relative to the
+    /// default prefix, e.g. `neovim` for `pkgs.neovim`.
+    pub fn lookup(&self, name: &str) -> Option<CacheRecord> {
+        self.get(name).or_else(|| self.get(&format!("{}.{}", self.default_prefix(), name)))
+    }
+
+    /// Whether the package was in the index of `system`.
+    pub fn available_on(&self, record: &CacheRecord, system: &str) -> bool {
+        if record.entry.systems.is_empty() {
+            self.header.platform == system
+        } else {
+            record.entry.systems.iter().any(|s| s == system)
+        }
+    }
+
+    /// Indexed systems the package is missing from.
+    pub fn unavailable_on(&self, record: &CacheRecord) -> Vec<&str> {
+        self.header.systems.iter()
+            .filter(|system| !self.available_on(record, system))
+            .map(|system| &system[..])
+            .collect()
+    }
+
+    /// Prefix of the first configured source, used for names given without one.
+    pub fn default_prefix(&self) -> &str {
+        self.header.sources.first().map_or("pkgs", |source| &source.prefix[..])
+    }
+
+    fn token(&self, token_id: usize) -> Option<&str> {
+        if token_id >= self.layout.num_tokens {
+            return None;
+        }
+        let start = read_u64(&self.data, self.layout.token_offsets + token_id * 8)?;
+        let end = read_u64(&self.data, self.layout.token_offsets + (token_id + 1) * 8)?;
+        let bytes = self.data.get(self.layout.tokens.saturating_add(start)..self.layout.tokens.saturating_add(end))?;
+        std::str::from_utf8(bytes.strip_suffix(b"\n")?).ok()
+    }
+
+    /// Id of the first token not sorting before `text`.
+    fn token_position(&self, text: &str) -> usize {
+        let (mut low, mut high) = (0, self.layout.num_tokens);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.token(mid).is_some_and(|token| token < text) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+
+    fn postings(&self, token_id: usize) -> impl Iterator<Item = u32> + '_ {
+        let start = read_u64(&self.data, self.layout.posting_offsets + token_id * 8).unwrap_or(0);
+        let end = read_u64(&self.data, self.layout.posting_offsets + (token_id + 1) * 8).unwrap_or(0);
+        let num_postings = (self.layout.trigrams - self.layout.postings) / 4;
+        (start..end.min(num_postings)).filter_map(|i| read_u32(&self.data, self.layout.postings + i * 4))
+    }
+
+    /// Ids of the tokens containing the trigram with hash `trigram`.
+    fn trigram_postings(&self, trigram: u32) -> impl Iterator<Item = u32> + '_ {
+        let (mut low, mut high) = (0, self.layout.num_trigrams);
+        while low < high {
+            let mid = (low + high) / 2;
+            match read_u32(&self.data, self.layout.trigrams + mid * 4) {
+                Some(hash) if hash < trigram => low = mid + 1,
+                _ => high = mid,
+            }
+        }
+        let found = read_u32(&self.data, self.layout.trigrams + low * 4) == Some(trigram);
+        let start = read_u64(&self.data, self.layout.trigram_offsets + low * 8).filter(|_| found).unwrap_or(0);
+        let end = read_u64(&self.data, self.layout.trigram_offsets + (low + 1) * 8).filter(|_| found).unwrap_or(0);
+        let num_postings = (self.layout.record_offsets - self.layout.trigram_postings) / 4;
+        (start..end.min(num_postings)).filter_map(|i| read_u32(&self.data, self.layout.trigram_postings + i * 4))
+    }
+
+    /// Ids of the tokens containing `part`. Terms shorter than a trigram only
+    /// match the start of tokens.
+    fn matching_tokens(&self, part: &str) -> Vec<usize> {
+        let inner = trigrams(part, false);
+        if inner.is_empty() {
+            return (self.token_position(part)..self.layout.num_tokens)
+                .take_while(|&token_id| self.token(token_id).is_some_and(|token| token.starts_with(part)))
+                .collect();
+        }
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for trigram in &inner {
+            for token_id in self.trigram_postings(*trigram) {
+                *counts.entry(token_id).or_default() += 1;
+            }
+        }
+        counts.into_iter()
+            .filter(|(_, count)| *count == inner.len())
+            .map(|(token_id, _)| token_id as usize)
+            .filter(|&token_id| self.token(token_id).is_some_and(|token| token.contains(part)))
+            .collect()
+    }
+
+    /// Ids of the tokens at most `typos` edits away from `part`. An edit
+    /// changes at most four of the trigrams of "^part$" (swapping two
+    /// characters does), so only tokens that share the others are compared.
+    fn similar_tokens(&self, part: &str, typos: usize) -> Vec<usize> {
+        let padded = trigrams(part, true);
+        // short terms may have no trigram left to share, require one anyway
+        let min_shared = padded.len().saturating_sub(4 * typos).max(1);
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for trigram in &padded {
+            for token_id in self.trigram_postings(*trigram) {
+                *counts.entry(token_id).or_default() += 1;
+            }
+        }
+        counts.into_iter()
+            .filter(|(_, count)| *count >= min_shared)
+            .map(|(token_id, _)| token_id as usize)
+            .filter(|&token_id| self.token(token_id).is_some_and(|token| edit_distance(part, token, typos).is_some()))
+            .collect()
+    }
+
+    /// Ids of the records whose key, pname or description may contain `text`,
+    /// or words a few typos away from its words. Returns None if `text` has
+    /// no tokens to look up, in which case every record is a candidate.
+    pub fn candidates(&self, text: &str) -> Option<BTreeSet<u32>> {
+        let mut candidates: Option<BTreeSet<u32>> = None;
+        for part in tokenize(text) {
+            let typos = max_typos(part.chars().count());
+            let mut token_ids = self.matching_tokens(&part);
+            if typos > 0 {
+                token_ids.extend(self.similar_tokens(&part, typos));
+            }
+            let ids: BTreeSet<u32> = token_ids.into_iter()
+                .flat_map(|token_id| self.postings(token_id))
+                .collect();
+            candidates = Some(match candidates {
+                None => ids,
+                Some(previous) => previous.intersection(&ids).copied().collect(),
+            });
+        }
+        candidates
+    }
+
+    /// Map of executable name to the ids of the records providing it.
+    pub fn programs(&self) -> BTreeMap<String, Vec<u32>> {
+        let end = self.data.len() - FOOTER_LEN;
+        match rmp_serde::from_slice(&self.data[self.layout.programs..end]) {
+            Ok(programs) => programs,
+            Err(e) => {
+                error!("cache programs index is corrupt: {}", e);
+                BTreeMap::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::cache::{SourceInfo, CACHE_SCHEMA_VERSION};
+
+    pub fn entry(pname: &str, description: &str) -> CacheEntry {
+        CacheEntry {
+            description: description.to_string(),
+            pname: pname.to_string(),
+            version: "1.0".to_string(),
+            meta: Default::default(),
+            systems: vec![],
+        }
+    }
+
+    /// Build and open a cache of `pkgs` holding `(attr, entry)` pairs. The
+    /// file is removed when the returned directory is dropped.
+    pub fn open_cache(packages: Vec<(&str, CacheEntry)>) -> (tempfile::TempDir, Cache) {
+        let header = CacheHeader {
+            schema_version: CACHE_SCHEMA_VERSION,
+            platform: "x86_64-linux".to_string(),
+            systems: vec!["x86_64-linux".to_string()],
+            entry_count: packages.len(),
+            sources: vec![SourceInfo { name: "nixpkgs".to_string(), prefix: "pkgs".to_string(), ..Default::default() }],
+            ..Default::default()
+        };
+        let source = SourceCache {
+            name: "nixpkgs".to_string(),
+            prefix: "pkgs".to_string(),
+            packages: packages.into_iter().map(|(attr, entry)| (attr.to_string(), entry)).collect(),
+            programs: vec![("rg".to_string(), "ripgrep".to_string())],
+        };
+        let previous_versions = HashMap::from([("pkgs.hello".to_string(), "0.9".to_string())]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.msgpack");
+        std::fs::write(&path, build_cache_file(&header, &[source], &previous_versions).unwrap()).unwrap();
+        let cache = Cache::open(&path).unwrap();
+        (dir, cache)
+    }
+
+    fn sample_cache() -> (tempfile::TempDir, Cache) {
+        open_cache(vec![
+            ("hello", entry("hello", "A program that produces a familiar, friendly greeting")),
+            ("ripgrep", entry("ripgrep", "Utility that combines the usability of The Silver Searcher with the raw speed of grep")),
+            ("neovim", entry("neovim", "Vim text editor fork focused on extensibility and agility")),
+            ("python3Packages.requests", entry("requests", "HTTP library for Python")),
+            ("qt5.qtbase", entry("qtbase", "A cross-platform application framework for C++")),
+        ])
+    }
+
+    fn keys(cache: &Cache, ids: Option<BTreeSet<u32>>) -> Vec<String> {
+        ids.unwrap().into_iter().filter_map(|id| cache.record(id as usize)).map(|record| record.key).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (_dir, cache) = sample_cache();
+        assert_eq!(cache.len(), 5);
+        let keys: Vec<String> = cache.package_iter().map(|record| record.key).collect();
+        assert_eq!(keys, ["pkgs.hello", "pkgs.neovim", "pkgs.python3Packages.requests", "pkgs.qt5.qtbase", "pkgs.ripgrep"]);
+        let hello = cache.get("pkgs.hello").unwrap();
+        assert_eq!(hello.source, "nixpkgs");
+        assert_eq!(hello.previous_version.as_deref(), Some("0.9"));
+        assert_eq!(cache.lookup("python3Packages.requests").unwrap().entry.pname, "requests");
+        assert!(cache.lookup("missing").is_none());
+        assert_eq!(cache.programs()["rg"], vec![4]);
+        assert!(cache.header.field_lengths.description > 0.0);
+    }
+
+    #[test]
+    fn candidates_match_substrings_and_typos() {
+        let (_dir, cache) = sample_cache();
+        assert_eq!(keys(&cache, cache.candidates("greeting")), ["pkgs.hello"]);
+        assert_eq!(keys(&cache, cache.candidates("grep")), ["pkgs.ripgrep"]);
+        assert_eq!(keys(&cache, cache.candidates("neovmi")), ["pkgs.neovim"]);
+        assert_eq!(keys(&cache, cache.candidates("qt")), ["pkgs.qt5.qtbase"]);
+        assert_eq!(keys(&cache, cache.candidates("python library")), ["pkgs.python3Packages.requests"]);
+        assert!(keys(&cache, cache.candidates("emacs")).is_empty());
+        assert!(cache.candidates("--").is_none());
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.msgpack");
+        let (_cache_dir, cache) = sample_cache();
+        let mut data = cache.data.to_vec();
+        data.truncate(data.len() - 1);
+        std::fs::write(&path, &data).unwrap();
+        assert!(Cache::open(&path).is_err());
+    }
+
+    #[test]
+    fn survives_corrupt_offsets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.msgpack");
+        let (_cache_dir, cache) = sample_cache();
+        let mut data = cache.data.to_vec();
+        // scramble the inner entries of the posting and record offset tables
+        for table in [cache.layout.posting_offsets, cache.layout.record_offsets] {
+            data[table + 8..table + 24].fill(0xff);
+        }
+        std::fs::write(&path, &data).unwrap();
+        let damaged = Cache::open(&path).unwrap();
+        assert!(damaged.record(0).is_none());
+        let _ = damaged.candidates("hello greeting");
+        assert!(damaged.package_iter().count() < 5);
+    }
+
+    #[test]
+    fn removes_file_with_corrupt_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.msgpack");
+        let (_cache_dir, cache) = sample_cache();
+        let mut data = cache.data.to_vec();
+        // 0xc1 is never used in msgpack
+        data[cache.layout.records] = 0xc1;
+        std::fs::write(&path, &data).unwrap();
+        let damaged = Cache::open(&path).unwrap();
+        assert!(damaged.record(1).is_some() && path.exists());
+        assert!(damaged.record(0).is_none());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt;
+
+use crate::index_source::diagnose_nix_error;
+
+/// Errors that end a fritz command. Each kind exits with its own code so
+/// that scripts can tell e.g. a missing package from a broken nix install.
+#[derive(Debug)]
+pub enum FritzError {
+    /// The fritz config file or `FRITZ_*` environment is invalid.
+    Config(String),
+    /// The package config file could not be read, parsed or written.
+    PackageConfig(String),
+    /// The package config file has no `home.packages` list.
+    MissingHomePackages(String),
+    /// Some requested packages or programs are not in the index.
+    PackageNotFound(Vec<String>),
+    /// No package provides the program. Without a programs database only
+    /// `mainProgram` is indexed, which most packages lack.
+    ProgramNotFound { program: String, programs_indexed: bool },
+    /// nix failed to produce the package index.
+    IndexFetch(String),
+    /// The cache could not be written, or is unreadable after rebuilding.
+    Cache(String),
+    /// git add, commit or push failed.
+    Vcs(String),
+    /// `home-manager switch` failed.
+    Switch(String),
+    /// The packages of the installed home-manager generation could not be listed.
+    Generation(String),
+    /// The package config file lists no packages.
+    NoPackages(String),
+}
+
+impl FritzError {
+    pub fn exit_code(&self) -> i32 {
+        // 1 is left for panics and 2 for clap's usage errors
+        match self {
+            FritzError::Config(_) => 3,
+            FritzError::PackageConfig(_) => 4,
+            FritzError::MissingHomePackages(_) => 5,
+            FritzError::PackageNotFound(_) | FritzError::ProgramNotFound { .. } => 6,
+            FritzError::IndexFetch(_) => 7,
+            FritzError::Cache(_) => 8,
+            FritzError::Vcs(_) => 9,
+            FritzError::Switch(_) => 10,
+            FritzError::Generation(_) => 11,
+            FritzError::NoPackages(_) => 12,
+        }
+    }
+
+    /// What the user can try next.
+    pub fn hint(&self) -> &'static str {
+        match self {
+            FritzError::Config(_) => "check the fritz config file and FRITZ_* environment variables",
+            FritzError::PackageConfig(_) => "check that `package_config_file` points to a valid .nix file",
+            FritzError::MissingHomePackages(_) => "add `home.packages = with pkgs; [ ];` to the package config file",
+            FritzError::PackageNotFound(_) => "try `fritz search <name>`, or `fritz cache refresh` if the package is new",
+            FritzError::ProgramNotFound { programs_indexed: true, .. } => "try `fritz search --bin <name>` for programs with similar names",
+            FritzError::ProgramNotFound { programs_indexed: false, .. } =>
+                "no programs database is indexed, set `index_programs_db` to a channel's programs.sqlite and run `fritz cache refresh`",
+            FritzError::IndexFetch(e) => diagnose_nix_error(e)
+                .unwrap_or("check that `nix search nixpkgs hello` works, or configure `index_source`"),
+            FritzError::Cache(_) => "check `cache_file_path`, or run `fritz cache clear` to rebuild the cache",
+            FritzError::Vcs(_) => "the package config file was changed, commit or push it by hand",
+            FritzError::Switch(_) => "the package config file was changed, run `home-manager switch` to see the error",
+            FritzError::Generation(_) => "check that `home-manager packages` works",
+            FritzError::NoPackages(_) => "add packages with `fritz add <name>`",
+        }
+    }
+}
+
+impl fmt::Display for FritzError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FritzError::Config(e) => write!(f, "invalid fritz config: {}", e),
+            FritzError::PackageConfig(e) => write!(f, "package config file: {}", e),
+            FritzError::MissingHomePackages(path) => write!(f, "home.packages not found in {}", path),
+            FritzError::PackageNotFound(names) => write!(f, "not found: {}", names.join(", ")),
+            FritzError::ProgramNotFound { program, .. } => write!(f, "no package provides {}", program),
+            FritzError::IndexFetch(e) => write!(f, "failed to fetch package index: {}", e),
+            FritzError::Cache(e) => write!(f, "package index cache: {}", e),
+            FritzError::Vcs(e) => write!(f, "git failed: {}", e),
+            FritzError::Switch(e) => write!(f, "home-manager switch failed: {}", e),
+            FritzError::Generation(e) => write!(f, "failed to list installed packages: {}", e),
+            FritzError::NoPackages(path) => write!(f, "no packages in {}", path),
+        }
+    }
+}
+
+impl std::error::Error for FritzError {}
@@ -0,0 +1,190 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::fs;
+use log::info;
+
+use crate::index_source::run_nix_streaming;
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum InputRef {
+    Node(String),
+    // `follows` paths, e.g. ["home-manager", "nixpkgs"]
+    Follows(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+struct LockedRef {
+    #[serde(rename = "type")]
+    ref_type: String,
+    rev: Option<String>,
+    owner: Option<String>,
+    repo: Option<String>,
+    url: Option<String>,
+    host: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LockNode {
+    #[serde(default)]
+    inputs: HashMap<String, InputRef>,
+    locked: Option<LockedRef>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FlakeLock {
+    nodes: HashMap<String, LockNode>,
+    root: String,
+}
+
+/// The part of `nix flake metadata --json` fritz needs.
+#[derive(Debug, Deserialize)]
+struct FlakeMetadata {
+    locked: LockedRef,
+}
+
+/// A flake input pinned by a flake.lock.
+#[derive(Debug, Clone)]
+pub struct LockedInput {
+    pub flake_ref: String,
+    pub rev: String,
+}
+
+/// Look for a flake.lock in the directory containing `file`, then in its
+/// parents up to the root of the git repository `file` is in.
+pub fn find_flake_lock(file: &Path) -> Option<PathBuf> {
+    for dir in file.ancestors().skip(1) {
+        let lock = dir.join("flake.lock");
+        if lock.is_file() {
+            return Some(lock);
+        }
+        // a flake.lock outside the repository belongs to some other flake
+        if dir.join(".git").exists() {
+            return None;
+        }
+    }
+    None
+}
+
+impl FlakeLock {
+    fn resolve_input(&self, input_ref: &InputRef) -> Option<&LockNode> {
+        match input_ref {
+            InputRef::Node(name) => self.nodes.get(name),
+            InputRef::Follows(path) => {
+                // follows paths are resolved from the root node
+                let mut node = self.nodes.get(&self.root)?;
+                for input in path {
+                    node = self.resolve_input(node.inputs.get(input)?)?;
+                }
+                Some(node)
+            }
+        }
+    }
+}
+
+fn locked_flake_ref(locked: &LockedRef, rev: &str) -> Option<String> {
+    match &locked.ref_type[..] {
+        "github" | "gitlab" | "sourcehut" => {
+            let host = locked.host.as_ref().map(|h| format!("?host={}", h)).unwrap_or_default();
+            Some(format!("{}:{}/{}/{}{}", locked.ref_type, locked.owner.as_ref()?, locked.repo.as_ref()?, rev, host))
+        },
+        "git" | "hg" => Some(format!("{}+{}?rev={}", locked.ref_type, locked.url.as_ref()?, rev)),
+        "tarball" | "file" => locked.url.clone(),
+        _ => None,
+    }
+}
+
+/// Read the revision of the root input `input` from the flake.lock at `lock_path`.
+pub fn read_locked_input(lock_path: &Path, input: &str) -> Result<LockedInput, String> {
+    let lock_str = fs::read_to_string(lock_path)
+        .map_err(|e| format!("failed to read {}: {}", lock_path.display(), e))?;
+    let lock: FlakeLock = serde_json::from_str(&lock_str)
+        .map_err(|e| format!("failed to parse {}: {}", lock_path.display(), e))?;
+    let root = lock.nodes.get(&lock.root)
+        .ok_or(format!("{} has no root node", lock_path.display()))?;
+    let node = root.inputs.get(input)
+        .and_then(|input_ref| lock.resolve_input(input_ref))
+        .ok_or(format!("input '{}' not found in {}", input, lock_path.display()))?;
+    let locked = node.locked.as_ref()
+        .ok_or(format!("input '{}' is not locked in {}", input, lock_path.display()))?;
+    let rev = locked.rev.clone()
+        .ok_or(format!("input '{}' has no locked revision in {}", input, lock_path.display()))?;
+    let flake_ref = locked_flake_ref(locked, &rev)
+        .ok_or(format!("unsupported input type '{}' for '{}' in {}", locked.ref_type, input, lock_path.display()))?;
+    info!("{} pins '{}' to {}", lock_path.display(), input, flake_ref);
+    Ok(LockedInput { flake_ref, rev })
+}
+
+/// Resolve `flake_ref` (e.g. `nixpkgs` from the flake registry) to the
+/// revision it points to now, using `nix flake metadata`.
+pub fn resolve_flake_ref(flake_ref: &str) -> Result<LockedInput, String> {
+    let metadata: FlakeMetadata = run_nix_streaming(&["flake", "metadata", "--json", flake_ref], |stdout| {
+        serde_json::from_reader(stdout).map_err(|e| e.to_string())
+    })?;
+    let locked = &metadata.locked;
+    let rev = locked.rev.clone().ok_or(format!("{} has no revision", flake_ref))?;
+    let locked_ref = locked_flake_ref(locked, &rev)
+        .ok_or(format!("unsupported flake type '{}' for {}", locked.ref_type, flake_ref))?;
+    info!("{} resolves to {}", flake_ref, locked_ref);
+    Ok(LockedInput { flake_ref: locked_ref, rev })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCK: &str = r#"{
+      "nodes": {
+        "home-manager": {
+          "inputs": { "nixpkgs": ["nixpkgs"] },
+          "locked": { "type": "github", "owner": "nix-community", "repo": "home-manager", "rev": "aaaa" }
+        },
+        "nixpkgs": {
+          "locked": { "type": "github", "owner": "NixOS", "repo": "nixpkgs", "rev": "bbbb" }
+        },
+        "hm-nixpkgs": {
+          "locked": { "type": "git", "url": "https://example.org/nixpkgs.git", "rev": "cccc" }
+        },
+        "root": {
+          "inputs": { "home-manager": "home-manager", "nixpkgs": "nixpkgs", "followed": ["home-manager", "nixpkgs"], "other": "hm-nixpkgs" }
+        }
+      },
+      "root": "root",
+      "version": 7
+    }"#;
+
+    fn write_lock(dir: &Path) -> PathBuf {
+        let path = dir.join("flake.lock");
+        fs::write(&path, LOCK).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_locked_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = write_lock(dir.path());
+        let nixpkgs = read_locked_input(&lock, "nixpkgs").unwrap();
+        assert_eq!(nixpkgs.rev, "bbbb");
+        assert_eq!(nixpkgs.flake_ref, "github:NixOS/nixpkgs/bbbb");
+        let followed = read_locked_input(&lock, "followed").unwrap();
+        assert_eq!(followed.flake_ref, "github:NixOS/nixpkgs/bbbb");
+        let other = read_locked_input(&lock, "other").unwrap();
+        assert_eq!(other.flake_ref, "git+https://example.org/nixpkgs.git?rev=cccc");
+        assert!(read_locked_input(&lock, "missing").is_err());
+    }
+
+    #[test]
+    fn finds_lock_within_the_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        fs::create_dir_all(repo.join(".git")).unwrap();
+        fs::create_dir_all(repo.join("fritz")).unwrap();
+        let file = repo.join("fritz/packages.nix");
+        // a flake.lock above the repository is not used
+        write_lock(dir.path());
+        assert_eq!(find_flake_lock(&file), None);
+        let lock = write_lock(&repo);
+        assert_eq!(find_flake_lock(&file), Some(lock));
+    }
+}
@@ -0,0 +1,62 @@
+/// How many typos a search term of `len` characters may contain. Short
+/// terms have to match exactly, anything else matches far too much.
+pub fn max_typos(len: usize) -> usize {
+    match len {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+/// Edit distance between `a` and `b`, counting insertions, deletions,
+/// substitutions and swaps of adjacent characters as one edit each.
+/// Returns None as soon as it is known to exceed `max`.
+pub fn edit_distance(a: &str, b: &str, max: usize) -> Option<usize> {
+    // most words of a package index differ in length too much to bother
+    if a.chars().count().abs_diff(b.chars().count()) > max {
+        return None;
+    }
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut before_previous: Vec<usize> = vec![0; b.len() + 1];
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let substitution = previous[j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            current[j] = substitution.min(previous[j] + 1).min(current[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before_previous[j - 2] + 1);
+            }
+        }
+        if current.iter().min().is_some_and(|&distance| distance > max) {
+            return None;
+        }
+        before_previous = std::mem::replace(&mut previous, current);
+    }
+    Some(previous[b.len()]).filter(|&distance| distance <= max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_edits() {
+        assert_eq!(edit_distance("firefox", "firefox", 2), Some(0));
+        assert_eq!(edit_distance("firfox", "firefox", 2), Some(1));
+        assert_eq!(edit_distance("neovmi", "neovim", 2), Some(1));
+        assert_eq!(edit_distance("fierfxo", "firefox", 2), Some(2));
+        assert_eq!(edit_distance("emacs", "vim", 2), None);
+        assert_eq!(edit_distance("vim", "neovim", 2), None);
+        assert_eq!(edit_distance("vim", "vin", 0), None);
+        assert_eq!(edit_distance("größe", "grösse", 2), Some(2));
+    }
+
+    #[test]
+    fn allows_more_typos_in_longer_terms() {
+        assert_eq!(max_typos(3), 0);
+        assert_eq!(max_typos(4), 1);
+        assert_eq!(max_typos(8), 2);
+    }
+}
@@ -0,0 +1,367 @@
+use serde::Deserialize;
+use serde::de::{Deserializer, MapAccess, Visitor};
+use std::collections::HashMap;
+use std::process::{ChildStdout, Command, ExitStatus, Stdio};
+use std::io::{BufReader, ErrorKind, Read};
+use std::fs::File;
+use std::marker::PhantomData;
+use std::fmt;
+use log::{info,warn};
+use indicatif::ProgressBar;
+
+use crate::app_config::SourceConfig;
+use crate::cache::CacheEntry;
+use crate::metadata::{PackageMeta, eval_metadata};
+
+const PROGRESS_INTERVAL: usize = 5000;
+
+/// A place fritz can fetch the package index from.
+pub trait IndexSource {
+    /// Short human readable description, used in log messages.
+    fn describe(&self) -> String;
+    /// Fetch the index, keyed by attribute path relative to the package set (e.g. `hello`).
+    fn fetch(&self, progress_bar: Option<&ProgressBar>) -> Result<HashMap<String, CacheEntry>, String>;
+    /// Whether `fetch` already fills in `meta`, so enriching it is pointless.
+    fn has_metadata(&self) -> bool {
+        false
+    }
+    /// Fetch `meta` for the given attribute paths, for sources whose index lacks it.
+    fn fetch_metadata(&self, _attrs: Vec<&String>, _progress_bar: Option<&ProgressBar>) -> Result<HashMap<String, PackageMeta>, String> {
+        Err(format!("{} can not provide package metadata, set a metadata_file", self.describe()))
+    }
+}
+
+/// `nix search <flake_ref> --json ^`, needs the `nix-command` and `flakes` features.
+/// Other systems than the current one are evaluated with `--system`.
+pub struct NixSearchSource {
+    pub flake_ref: String,
+    pub system: String,
+}
+
+/// `nix-env -qaP --json --meta`, works with channels only.
+pub struct NixEnvSource {
+    /// Only index this channel, all of them if empty.
+    pub channel: String,
+    pub system: String,
+}
+
+/// A saved `nix search --json` output file.
+pub struct JsonFileSource {
+    pub path: String,
+    pub system: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NixEnvEntry {
+    pname: String,
+    version: String,
+    #[serde(default)]
+    meta: serde_json::Value,
+}
+
+pub fn get_index_source(config: &SourceConfig, system: &str) -> Result<Box<dyn IndexSource>, String> {
+    match &config.index_source[..] {
+        "nix-search" => Ok(Box::new(NixSearchSource { flake_ref: config.flake_ref.clone(), system: system.to_string() })),
+        "nix-env" => Ok(Box::new(NixEnvSource { channel: config.channel.clone(), system: system.to_string() })),
+        "json-file" => Ok(Box::new(JsonFileSource { path: config.json_file.clone(), system: system.to_string() })),
+        other => Err(format!("unknown index source '{}' for source '{}', expected one of nix-search, nix-env, json-file", other, config.name)),
+    }
+}
+
+/// Deserializes a JSON object entry by entry, passing each key/value pair
+/// through `convert` so the whole document never has to be held in memory.
+/// Entries `convert` returns None for are skipped.
+struct StreamingMapVisitor<'a, T, O, F> {
+    convert: F,
+    progress_bar: Option<&'a ProgressBar>,
+    _entry: PhantomData<(T, O)>,
+}
+
+impl<'de, 'a, T, O, F> Visitor<'de> for StreamingMapVisitor<'a, T, O, F>
+where
+    T: Deserialize<'de>,
+    F: FnMut(String, T) -> Option<(String, O)>,
+{
+    type Value = HashMap<String, O>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a JSON object of packages")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut packages = HashMap::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some((key, value)) = map.next_entry::<String, T>()? {
+            if let Some((attr, entry)) = (self.convert)(key, value) {
+                packages.insert(attr, entry);
+            }
+            if packages.len() % PROGRESS_INTERVAL == 0 {
+                if let Some(pb) = self.progress_bar { pb.set_message(format!("parsed {} packages", packages.len())); }
+            }
+        }
+        Ok(packages)
+    }
+}
+
+pub fn parse_streaming<R, T, O, F>(reader: R, progress_bar: Option<&ProgressBar>, convert: F) -> Result<HashMap<String, O>, String>
+where
+    R: Read,
+    T: for<'de> Deserialize<'de>,
+    F: FnMut(String, T) -> Option<(String, O)>,
+{
+    let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(reader));
+    let visitor = StreamingMapVisitor { convert, progress_bar, _entry: PhantomData };
+    let packages = deserializer.deserialize_map(visitor).map_err(|e| e.to_string())?;
+    deserializer.end().map_err(|e| e.to_string())?;
+    Ok(packages)
+}
+
+/// Parse `nix search --json` output, stripping the `legacyPackages.<system>.`
+/// or `packages.<system>.` prefix from the keys and skipping packages of
+/// other systems.
+fn parse_nix_search_json<R: Read>(reader: R, system: &str, progress_bar: Option<&ProgressBar>) -> Result<HashMap<String, CacheEntry>, String> {
+    parse_streaming(reader, progress_bar, |key: String, entry: CacheEntry| {
+        let Some((_, rest)) = key.split_once('.').filter(|(output, _)| ["legacyPackages", "packages"].contains(output)) else {
+            return Some((key, entry));
+        };
+        match rest.split_once('.') {
+            Some((key_system, attr)) if key_system == system => Some((attr.to_string(), entry)),
+            _ => None,
+        }
+    })
+}
+
+/// How running an external command failed.
+enum CommandError {
+    Spawn(std::io::Error),
+    Exit { status: ExitStatus, stderr: String },
+    Parse(String),
+}
+
+impl CommandError {
+    fn describe(&self, command: &Command) -> String {
+        match self {
+            CommandError::Spawn(e) if e.kind() == ErrorKind::NotFound =>
+                format!("{:?} is not installed or not on PATH", command.get_program()),
+            CommandError::Spawn(e) => format!("failed to run {:?}: {}", command, e),
+            CommandError::Exit { status, stderr } =>
+                format!("{:?} failed ({}):\n{}", command, status, error_lines(stderr)),
+            CommandError::Parse(e) => format!("failed to parse output of {:?}: {}", command, e),
+        }
+    }
+}
+
+/// nix prints progress before errors, keep the error and what follows it.
+fn error_lines(stderr: &str) -> String {
+    let lines: Vec<&str> = stderr.trim().lines().collect();
+    let start = lines.iter().position(|line| line.trim_start().starts_with("error:"))
+        .unwrap_or(lines.len().saturating_sub(5));
+    lines[start..].join("\n")
+}
+
+fn missing_experimental_features(stderr: &str) -> bool {
+    stderr.contains("experimental Nix feature") && stderr.contains("is disabled")
+}
+
+/// A suggestion for common reasons `nix` fails, given its error output.
+pub fn diagnose_nix_error(stderr: &str) -> Option<&'static str> {
+    if missing_experimental_features(stderr) {
+        Some("enable flakes with `experimental-features = nix-command flakes` in nix.conf, or use `index_source = \"nix-env\"`")
+    } else if stderr.contains("in the flake registries") {
+        Some("the flake is not in the registry, set `index_flake_ref` to e.g. github:NixOS/nixpkgs/nixos-unstable")
+    } else if stderr.contains("resolve host") || stderr.contains("unable to download") {
+        Some("nix could not reach the network, try again once online or index a local flake")
+    } else {
+        None
+    }
+}
+
+fn run_command<T, F>(command: &mut Command, parse: F) -> Result<T, CommandError>
+where
+    F: FnOnce(ChildStdout) -> Result<T, String>,
+{
+    let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()
+        .map_err(CommandError::Spawn)?;
+    let stdout = child.stdout.take().ok_or(CommandError::Parse("no output".to_string()))?;
+    // drain stderr on another thread so a chatty command can not block on it
+    let stderr = child.stderr.take().map(|mut stderr| std::thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stderr.read_to_string(&mut output);
+        output
+    }));
+    let parsed = parse(stdout);
+    // reap the child even if parsing failed
+    let status = child.wait().map_err(CommandError::Spawn)?;
+    let stderr = stderr.and_then(|thread| thread.join().ok()).unwrap_or_default();
+    if !status.success() {
+        return Err(CommandError::Exit { status, stderr });
+    }
+    parsed.map_err(CommandError::Parse)
+}
+
+/// Run `command`, feeding its stdout to `parse` as it is produced.
+pub fn run_streaming<T, F>(command: &mut Command, parse: F) -> Result<T, String>
+where
+    F: FnOnce(ChildStdout) -> Result<T, String>,
+{
+    run_command(command, parse).map_err(|e| e.describe(command))
+}
+
+/// Run `nix` with `args` like `run_streaming`, retrying with the
+/// `nix-command` and `flakes` features enabled if nix says they are disabled.
+pub fn run_nix_streaming<T, F>(args: &[&str], mut parse: F) -> Result<T, String>
+where
+    F: FnMut(ChildStdout) -> Result<T, String>,
+{
+    let mut command = Command::new("nix");
+    command.args(args);
+    match run_command(&mut command, &mut parse) {
+        Err(CommandError::Exit { stderr, .. }) if missing_experimental_features(&stderr) => {
+            warn!("nix-command or flakes are not enabled, retrying with --extra-experimental-features");
+            let mut command = Command::new("nix");
+            command.arg("--extra-experimental-features").arg("nix-command flakes").args(args);
+            run_command(&mut command, parse).map_err(|e| e.describe(&command))
+        },
+        result => result.map_err(|e| e.describe(&command)),
+    }
+}
+
+impl IndexSource for NixSearchSource {
+    fn describe(&self) -> String {
+        format!("nix search {}", self.flake_ref)
+    }
+
+    fn fetch(&self, progress_bar: Option<&ProgressBar>) -> Result<HashMap<String, CacheEntry>, String> {
+        if let Some(pb) = progress_bar { pb.set_message(format!("fetching {} index", self.flake_ref)); }
+        let packages = run_nix_streaming(&["search", "--system", &self.system, &self.flake_ref, "--json", "^"],
+                                         |stdout| parse_nix_search_json(stdout, &self.system, progress_bar))?;
+        info!("completed nix search command");
+        if let Some(pb) = progress_bar { pb.inc(1); }
+        Ok(packages)
+    }
+
+    fn fetch_metadata(&self, attrs: Vec<&String>, progress_bar: Option<&ProgressBar>) -> Result<HashMap<String, PackageMeta>, String> {
+        eval_metadata(&self.flake_ref, &self.system, attrs, progress_bar)
+    }
+}
+
+impl IndexSource for NixEnvSource {
+    fn describe(&self) -> String {
+        match &self.channel[..] {
+            "" => "nix-env -qaP".to_string(),
+            channel => format!("nix-env -qaP -A {}", channel),
+        }
+    }
+
+    fn has_metadata(&self) -> bool {
+        true
+    }
+
+    fn fetch(&self, progress_bar: Option<&ProgressBar>) -> Result<HashMap<String, CacheEntry>, String> {
+        if let Some(pb) = progress_bar { pb.set_message("fetching channel index"); }
+        let mut command = Command::new("nix-env");
+        command.arg("-qaP").arg("--json").arg("--meta").arg("--option").arg("system").arg(&self.system);
+        if !self.channel.is_empty() {
+            command.arg("-A").arg(&self.channel);
+        }
+        let packages = run_streaming(&mut command, |stdout| {
+            parse_nix_env_json(stdout, &self.channel, progress_bar)
+        })?;
+        info!("completed nix-env command");
+        if let Some(pb) = progress_bar { pb.inc(1); }
+        Ok(packages)
+    }
+}
+
+/// Parse `nix-env -qaP --json --meta` output. Attribute paths are prefixed
+/// with the channel name, e.g. `nixpkgs.hello`; the prefix is stripped and,
+/// if `channel` is set, packages of other channels are skipped.
+fn parse_nix_env_json<R: Read>(reader: R, channel: &str, progress_bar: Option<&ProgressBar>) -> Result<HashMap<String, CacheEntry>, String> {
+    parse_streaming(reader, progress_bar, |key: String, entry: NixEnvEntry| {
+        let (key_channel, attr) = key.split_once('.').unwrap_or(("", &key));
+        if !channel.is_empty() && key_channel != channel {
+            return None;
+        }
+        Some((attr.to_string(), CacheEntry {
+            description: entry.meta.get("description").and_then(|d| d.as_str()).unwrap_or_default().to_string(),
+            pname: entry.pname,
+            version: entry.version,
+            meta: PackageMeta::from_nix_meta(&entry.meta),
+            systems: vec![],
+        }))
+    })
+}
+
+impl IndexSource for JsonFileSource {
+    fn describe(&self) -> String {
+        format!("json file {}", self.path)
+    }
+
+    fn fetch(&self, progress_bar: Option<&ProgressBar>) -> Result<HashMap<String, CacheEntry>, String> {
+        if let Some(pb) = progress_bar { pb.set_message(format!("reading {}", self.path)); }
+        let file = File::open(&self.path).map_err(|e| format!("failed to read {}: {}", self.path, e))?;
+        let packages = parse_nix_search_json(file, &self.system, progress_bar)
+            .map_err(|e| format!("failed to parse {}: {}", self.path, e))?;
+        if let Some(pb) = progress_bar { pb.inc(1); }
+        Ok(packages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEARCH_JSON: &str = r#"{
+      "legacyPackages.x86_64-linux.hello": { "pname": "hello", "version": "2.12.1", "description": "A program that produces a familiar, friendly greeting" },
+      "legacyPackages.aarch64-darwin.hello": { "pname": "hello", "version": "2.12.1", "description": "A program that produces a familiar, friendly greeting" },
+      "legacyPackages.x86_64-linux.python3Packages.requests": { "pname": "requests", "version": "2.31.0", "description": "HTTP library for Python" },
+      "packages.x86_64-linux.tool": { "pname": "tool", "version": "1.0", "description": "" },
+      "packages.aarch64-darwin.tool": { "pname": "tool", "version": "1.0", "description": "" },
+      "hello-unwrapped": { "pname": "hello", "version": "2.12.1", "description": "" }
+    }"#;
+
+    #[test]
+    fn parses_nix_search_json_of_one_system() {
+        let packages = parse_nix_search_json(SEARCH_JSON.as_bytes(), "x86_64-linux", None).unwrap();
+        let mut attrs: Vec<&String> = packages.keys().collect();
+        attrs.sort();
+        assert_eq!(attrs, ["hello", "hello-unwrapped", "python3Packages.requests", "tool"]);
+        assert_eq!(packages["python3Packages.requests"].pname, "requests");
+        assert_eq!(packages["hello"].version, "2.12.1");
+    }
+
+    #[test]
+    fn parses_nix_env_json_of_one_channel() {
+        let json = r#"{
+          "nixos.hello": { "pname": "hello", "version": "2.12.1", "meta": { "description": "Hello" } },
+          "nixos-unstable.hello": { "pname": "hello", "version": "2.12.2", "meta": { "description": "Hello" } }
+        }"#;
+        let packages = parse_nix_env_json(json.as_bytes(), "nixos-unstable", None).unwrap();
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages["hello"].version, "2.12.2");
+        assert_eq!(packages["hello"].description, "Hello");
+    }
+
+    #[test]
+    fn streaming_skips_entries_and_rejects_trailing_data() {
+        let json = r#"{ "a": 1, "b": 2, "c": 3 }"#;
+        let odd = parse_streaming(json.as_bytes(), None, |key: String, value: u32| {
+            (value % 2 == 1).then_some((key, value))
+        }).unwrap();
+        assert_eq!(odd.len(), 2);
+        assert_eq!(odd["c"], 3);
+        assert!(parse_streaming(r#"{ "a": 1 } {"#.as_bytes(), None, |key: String, value: u32| Some((key, value))).is_err());
+        assert!(parse_streaming(r#"[1, 2]"#.as_bytes(), None, |key: String, value: u32| Some((key, value))).is_err());
+    }
+
+    #[test]
+    fn keeps_nix_errors_and_diagnoses_them() {
+        let stderr = "evaluating 'legacyPackages'...\ncopying path\nerror: cannot find flake 'flake:nixpkgz' in the flake registries\n  at foo\n";
+        assert_eq!(error_lines(stderr), "error: cannot find flake 'flake:nixpkgz' in the flake registries\n  at foo");
+        assert!(diagnose_nix_error(stderr).unwrap().contains("index_flake_ref"));
+        let disabled = "error: experimental Nix feature 'flakes' is disabled; add '--extra-experimental-features flakes' to enable it";
+        assert!(missing_experimental_features(disabled));
+        assert!(diagnose_nix_error("error: attribute 'foo' missing").is_none());
+        // without an error line the end of the output is kept
+        assert_eq!(error_lines("1\n2\n3\n4\n5\n6\n7"), "3\n4\n5\n6\n7");
+    }
+}
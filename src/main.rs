@@ -1,26 +1,32 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand, error::ErrorKind};
 use serde::{Serialize, Deserialize};
 use figment::{Figment, providers::{Serialized, Toml, Env, Format}};
-use rnix::{self, SyntaxKind, SyntaxNode};
 use log::{error,info};
-use env_logger;
-use colored::*;
-use dialoguer::FuzzySelect;
-use regex::Regex;
 use std::cmp::min;
 use std::process::Command;
 use indicatif::ProgressBar;
 use std::time::Duration;
-use std::env::var;
+use std::collections::BTreeMap;
+use colored::Colorize;
 
 mod search;
 mod app_config;
 mod nix_config;
 mod cache;
+mod cache_file;
+mod index_source;
+mod flake_lock;
+mod platform;
+mod metadata;
+mod programs;
+mod error;
+mod outdated;
+mod fuzzy;
 use crate::nix_config::get_nix_config;
-use crate::app_config::AppConfig;
-use crate::search::SearchResult;
-use crate::cache::get_cache;
+use crate::app_config::{AppConfig, config_home};
+use crate::search::{Installed, SearchFilters, SearchResult};
+use crate::cache::{get_cache, refresh_cache, refresh_cache_if_changed, build_cache, clear_cache, read_cache_header, migrate_legacy_cache};
+use crate::error::FritzError;
 
 #[derive(Parser, Debug, Serialize, Deserialize)]
 #[command(name = "fritz")]
@@ -30,6 +36,9 @@ struct Cli {
     dry_run: bool,
     #[arg(long)]
     config: Option<String>,
+    /// Never refresh the package index, use the cache however old it is
+    #[arg(long)]
+    offline: bool,
     #[command(subcommand)]
     command: Commands,
 }
@@ -46,128 +55,182 @@ enum Commands {
     },
     #[command(arg_required_else_help = true)]
     Search {
+        /// Words, "phrases", -exclusions and pname:, desc:, key: or version: terms, after any options
+        #[arg(allow_hyphen_values = true)]
         strings: Vec<String>,
+        /// Match executable names instead of package names and descriptions
+        #[arg(long)]
+        bin: bool,
+        /// Only show packages available on this system, e.g. aarch64-darwin
+        #[arg(long)]
+        system: Option<String>,
+        /// Only show packages in the package config file
+        #[arg(long, conflicts_with = "not_installed")]
+        installed: bool,
+        /// Only show packages not in the package config file
+        #[arg(long)]
+        not_installed: bool,
+        /// Only show packages in this package set, e.g. python3Packages
+        #[arg(long)]
+        namespace: Option<String>,
+        /// Only show packages outside of nested package sets
+        #[arg(long, conflicts_with = "namespace")]
+        top_level_only: bool,
+        /// Only show packages with a matching license, e.g. MIT (needs package metadata)
+        #[arg(long)]
+        license: Option<String>,
+        /// Hide unfree packages (needs package metadata)
+        #[arg(long)]
+        no_unfree: bool,
+        /// Hide broken packages (needs package metadata)
+        #[arg(long)]
+        no_broken: bool,
+    },
+    /// Show which packages provide an executable
+    #[command(arg_required_else_help = true)]
+    Provides {
+        program: String,
+    },
+    List,
+    /// List packages whose version changed in the last index refresh
+    Outdated {
+        /// Compare with the installed home-manager generation instead
+        #[arg(long)]
+        installed: bool,
+    },
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+}
+
+#[derive(Debug, Subcommand, Serialize, Deserialize)]
+enum CacheCommands {
+    /// Show age, size and origin of the package index cache
+    Status,
+    /// Rebuild the package index cache now
+    Refresh {
+        /// Keep the cache if the flake refs of all sources still resolve to the revisions it was built from
+        #[arg(long)]
+        if_changed: bool,
+    },
+    /// Delete the package index cache
+    Clear,
+    /// Build a package index cache at another path, e.g. a shared system cache
+    Build {
+        /// Where to write the cache, default `system_cache_file_path`
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Print every cached package
+    Export {
+        #[arg(long)]
+        json: bool,
     },
-    List
 }
 
 
 fn pretty_format_result(result: &SearchResult) -> String {
-    format!("{} \t [{}] \t {}", result.full_key, result.version, result.description)
+    let key = match result.installed {
+	Installed::Config => format!("{} {}", result.full_key.green().bold(), "[installed]".green()),
+	Installed::HomeManager => format!("{} {}", result.full_key, "[home-manager]".yellow()),
+	Installed::No => result.full_key.clone(),
+    };
+    format!("{} \t [{}] \t ({}) {}", key, result.version, result.source, result.description)
 }
 
 fn pretty_print_result(result: &SearchResult) {
     println!("{}", pretty_format_result(result));
 }
 
-fn get_search_result_choice(results: &[SearchResult], max_length: usize) -> Option<usize> {
-    let pretty_results: Vec<String> = results.iter().map(|x| pretty_format_result(x)).collect();
-    FuzzySelect::new()
-        .with_prompt("Choose package to install:")
-        .items(&pretty_results)
-        .max_length(max_length)
-        .interact_opt()
-        .unwrap()
-}
-
-fn run_hm_update(progress_bar: &ProgressBar, app_config: &AppConfig) {
+fn run_hm_update(progress_bar: &ProgressBar, app_config: &AppConfig) -> Result<(), FritzError> {
     info!("running home-manager switch");
     progress_bar.set_message("running home-manager switch");
-    let update_command = Command::new(&app_config.switch_base_command).arg("switch").output();
-    match update_command {
-	Ok(x) => {
-	    info!("home-manager switch output: ");
-	    info!("{:?}", x);
-	},
-	Err(e) => {
-	    error!("home-manager switch error! output: ");
-	    error!("{:?}", e);
-	}
+    let update_command = Command::new(&app_config.switch_base_command).arg("switch").output()
+	.map_err(|e| FritzError::Switch(format!("failed to run {}: {}", app_config.switch_base_command, e)))?;
+    info!("home-manager switch output: ");
+    info!("{:?}", update_command);
+    if !update_command.status.success() {
+	let stderr = String::from_utf8_lossy(&update_command.stderr);
+	let reason = stderr.trim().lines().last().map_or(update_command.status.to_string(), str::to_string);
+	return Err(FritzError::Switch(reason));
     }
-    progress_bar.inc(1)
+    progress_bar.inc(1);
+    Ok(())
 }
 
-fn add_changes(app_config: &AppConfig, progress_bar: &ProgressBar) {
-    info!("addting config changes");
-    progress_bar.set_message("git add");
+/// Run git in the directory of the package config file.
+fn run_git(app_config: &AppConfig, args: &[&str]) -> Result<(), FritzError> {
     let config_file = std::path::Path::new(&app_config.package_config_file);
-    let config_dir = config_file.parent().unwrap();
-    let update_command_output = Command::new("git").arg("add").arg(format!("{}", config_file.file_name().unwrap().to_str().unwrap())).current_dir(config_dir).output().expect("failed to run git");
-    let update_command_status = update_command_output.status;
-    if update_command_status.success() {
-	info!("git add output: ");
+    let config_dir = config_file.parent().unwrap_or(std::path::Path::new("."));
+    let update_command_output = Command::new("git").args(args).current_dir(config_dir).output()
+	.map_err(|e| FritzError::Vcs(format!("failed to run git: {}", e)))?;
+    if update_command_output.status.success() {
+	info!("git {} output: ", args[0]);
 	info!("{:?}", &update_command_output.stdout);
+	Ok(())
     } else {
-	error!("git add error! ");
-	error!("{:?}", &update_command_output.stderr);
+	Err(FritzError::Vcs(format!("git {}: {}", args[0], String::from_utf8_lossy(&update_command_output.stderr).trim())))
     }
+}
+
+fn add_changes(app_config: &AppConfig, progress_bar: &ProgressBar) -> Result<(), FritzError> {
+    info!("addting config changes");
+    progress_bar.set_message("git add");
+    let config_file = std::path::Path::new(&app_config.package_config_file);
+    let file_name = config_file.file_name().and_then(|name| name.to_str())
+	.ok_or(FritzError::PackageConfig(format!("invalid path {}", app_config.package_config_file)))?;
+    run_git(app_config, &["add", file_name])?;
     progress_bar.inc(1);
+    Ok(())
 }
 
 
-fn commit_changes(app_config: &AppConfig, progress_bar: &ProgressBar) {
-    add_changes(app_config, progress_bar);
+fn commit_changes(app_config: &AppConfig, progress_bar: &ProgressBar) -> Result<(), FritzError> {
+    add_changes(app_config, progress_bar)?;
     progress_bar.set_message("git commit");
     info!("committing config changes");
-    let config_file = std::path::Path::new(&app_config.package_config_file);
-    let config_dir = config_file.parent().unwrap();
     let bar = ProgressBar::new_spinner();
     bar.enable_steady_tick(Duration::from_millis(100));
     let args: String = std::env::args().collect::<Vec<String>>().join(" ");
     let commit_msg = format!("fritz package update, command: {}", args);
-    let update_command_output = Command::new("git").arg("commit").arg("-m").arg(commit_msg).current_dir(config_dir).output().expect("failed to run git");
-    let update_command_status = update_command_output.status;
+    let committed = run_git(app_config, &["commit", "-m", &commit_msg]);
     bar.finish();
-    if update_command_status.success() {
-	info!("git commit output: ");
-	info!("{:?}", &update_command_output.stdout);
-    } else {
-	error!("git commit error! ");
-	error!("{:?}", &update_command_output.stdout);
-	error!("{:?}", &update_command_output.stderr);
-    }
+    committed?;
     progress_bar.inc(1);
     match app_config.push_change {
-	true => { push_changes(&app_config, &progress_bar); },
-	false => { info!("pushing config changes is disabled") }
+	true => { push_changes(app_config, progress_bar) },
+	false => { info!("pushing config changes is disabled"); Ok(()) }
     }
 }
 
-fn push_changes(app_config: &AppConfig, progress_bar: &ProgressBar) {
+fn push_changes(app_config: &AppConfig, progress_bar: &ProgressBar) -> Result<(), FritzError> {
     info!("pushing config changes");
     progress_bar.set_message("git push");
-    let config_file = std::path::Path::new(&app_config.package_config_file);
-    let config_dir = config_file.parent().unwrap();
-    let update_command_output = Command::new("git").arg("push").current_dir(config_dir).output().expect("failed to run git");
-    let update_command_status = update_command_output.status;
-    if update_command_status.success() {
-	info!("git push output: ");
-	info!("{:?}", &update_command_output.stdout);
-    } else {
-	error!("git push error! ");
-	error!("{:?}", &update_command_output.stderr);
-    }
+    run_git(app_config, &["push"])?;
     progress_bar.inc(1);
+    Ok(())
 }
 
-fn get_default_config_file() -> String {
-    let config_home = var("XDG_CONFIG_HOME").or_else(|_| var("HOME").map(|home| format!("{}/.config", home))).unwrap();
-    format!("{}/fritz/config.toml", config_home)
+fn get_default_config_file() -> Result<String, FritzError> {
+    let config_home = config_home()
+	.ok_or(FritzError::Config("neither XDG_CONFIG_HOME nor HOME is set, pass --config".to_string()))?;
+    Ok(format!("{}/fritz/config.toml", config_home))
 }
 
-fn remove_packages(packages: &Vec<String>, app_config: &AppConfig, cli_args: &Cli, progress_bar: &ProgressBar) {
+fn remove_packages(packages: &Vec<String>, app_config: &AppConfig, cli_args: &Cli, progress_bar: &ProgressBar) -> Result<(), FritzError> {
     progress_bar.set_message("removing packages from config file");
-    let nix_config = get_nix_config(&app_config);
-    let change_made = nix_config.remove_packages(&packages, cli_args.dry_run);
+    let nix_config = get_nix_config(app_config)?;
+    let change_made = nix_config.remove_packages(packages, cli_args.dry_run)?;
     progress_bar.inc(1);
     if change_made && !cli_args.dry_run {
 	match app_config.hm_switch {
-	    true => { run_hm_update(progress_bar, app_config); },
+	    true => { run_hm_update(progress_bar, app_config)?; },
 	    false => { info!("home-manager switch is disabled") }
 	}
 	match app_config.commit_change {
 	    true => {
-		commit_changes(&app_config, progress_bar);
+		commit_changes(app_config, progress_bar)?;
 	    },
 	    false => { info!("committing config changes is disabled") }
 	}
@@ -177,28 +240,23 @@ fn remove_packages(packages: &Vec<String>, app_config: &AppConfig, cli_args: &Cl
 			 + 2*app_config.commit_change as u64
 			 + app_config.push_change as u64);
     }
+    Ok(())
 }
 
-fn add_packages(packages: &Vec<String>, app_config: &AppConfig, cli_args: &Cli, progress_bar: &ProgressBar) {
+fn add_packages(packages: &Vec<String>, app_config: &AppConfig, cli_args: &Cli, progress_bar: &ProgressBar) -> Result<(), FritzError> {
     progress_bar.set_message("adding packages to config file");
-    let nix_config = get_nix_config(&app_config);
-    let cache = match get_cache(&app_config) {
-	Ok(x) => x,
-	Err(e) => {
-	    error!("failed to read cache: {}", e);
-	    return
-	}
-    };
+    let nix_config = get_nix_config(app_config)?;
+    let cache = get_cache(app_config)?;
     progress_bar.inc(1);
-    let change_made = nix_config.add_packages(&packages, &cache, cli_args.dry_run);
+    let change_made = nix_config.add_packages(packages, &cache, cli_args.dry_run)?;
     if change_made && !cli_args.dry_run {
 	match app_config.hm_switch {
-	    true => { run_hm_update(progress_bar, app_config); },
+	    true => { run_hm_update(progress_bar, app_config)?; },
 	    false => { info!("home-manager switch is disabled") }
 	}
 	match app_config.commit_change {
 	    true => {
-		commit_changes(&app_config, progress_bar);
+		commit_changes(app_config, progress_bar)?;
 	    },
 	    false => { info!("committing config changes is disabled") }
 	}
@@ -208,60 +266,224 @@ fn add_packages(packages: &Vec<String>, app_config: &AppConfig, cli_args: &Cli,
 			 + 2*app_config.commit_change as u64
 			 + app_config.push_change as u64);
     }
+    Ok(())
 }
 
-fn list_packages(app_config: &AppConfig) {
-    let nix_config = get_nix_config(&app_config);
+fn list_packages(app_config: &AppConfig) -> Result<(), FritzError> {
+    let nix_config = get_nix_config(app_config)?;
     match nix_config.list_current_packages() {
-	Some(found_packages) => {
+	Some(found_packages) if !found_packages.is_empty() => {
 	    for pkg in found_packages {
 	    println!("{}", pkg);
 	    }
+	    Ok(())
+	}
+	Some(_) => Err(FritzError::NoPackages(app_config.package_config_file.clone())),
+	None => Err(FritzError::PackageConfig(format!("could not read home.packages in {}", app_config.package_config_file))),
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    if age.as_secs() < 3600 {
+        format!("{:.1} minutes", age.as_secs_f32() / 60.0)
+    } else {
+        format!("{:.1} hours", age.as_secs_f32() / 3600.0)
+    }
+}
+
+fn print_cache_status(app_config: &AppConfig) {
+    if !app_config.system_cache_file_path.is_empty() {
+	print_cache_file_status(&app_config.system_cache_file_path);
+	println!();
+    }
+    print_cache_file_status(&app_config.cache_file_path);
+}
+
+fn print_cache_file_status(cache_file_path: &str) {
+    let cache_path = std::path::Path::new(cache_file_path);
+    let size = match cache_path.metadata() {
+	Ok(metadata) => metadata.len(),
+	Err(_) => {
+	    println!("no cache at {}", cache_file_path);
+	    return
+	}
+    };
+    println!("cache file: {} ({:.1} MB)", cache_file_path, size as f32 / 1_000_000.0);
+    match read_cache_header(cache_path) {
+	Ok(header) => {
+	    println!("age:        {}", format_age(header.age()));
+	    println!("platform:   {}", header.platform);
+	    println!("entries:    {}", header.entry_count);
+	    for source in header.sources {
+		let revision = source.revision.map(|rev| format!(" at {}", rev)).unwrap_or_default();
+		println!("source:     {} ({} entries) from {}{}", source.name, source.entry_count, source.origin, revision);
+	    }
+	},
+	Err(e) => {
+	    println!("unusable cache, will be rebuilt on next use: {}", e);
+	}
+    }
+}
+
+fn export_cache(app_config: &AppConfig, json: bool) -> Result<(), FritzError> {
+    let cache = get_cache(app_config)?;
+    // records are stored sorted by key
+    if json {
+	let packages: BTreeMap<String, serde_json::Value> = cache.package_iter().map(|record| {
+	    (record.key, serde_json::json!({
+		"source": record.source,
+		"pname": record.entry.pname,
+		"version": record.entry.version,
+		"description": record.entry.description,
+		"meta": record.entry.meta,
+	    }))
+	}).collect();
+	match serde_json::to_writer(std::io::stdout(), &packages) {
+	    Ok(_) => println!(),
+	    Err(e) => error!("failed to write json: {}", e),
 	}
-	None => {
-	    error!("no packages found.");
+    } else {
+	for record in cache.package_iter() {
+	    println!("{}\t{}\t{}", record.key, record.entry.version, record.entry.description);
 	}
     }
+    Ok(())
+}
+
+fn run_cache_command(command: &CacheCommands, app_config: &AppConfig) -> Result<(), FritzError> {
+    match command {
+	CacheCommands::Status => print_cache_status(app_config),
+	CacheCommands::Refresh { if_changed } => {
+	    if *if_changed {
+		refresh_cache_if_changed(app_config)?;
+	    } else {
+		refresh_cache(app_config)?;
+	    }
+	    print_cache_status(app_config);
+	},
+	CacheCommands::Clear => {
+	    match clear_cache(app_config)? {
+		true => println!("removed {}", app_config.cache_file_path),
+		false => println!("no cache at {}", app_config.cache_file_path),
+	    }
+	},
+	CacheCommands::Build { output } => {
+	    let output = output.as_deref().unwrap_or(&app_config.system_cache_file_path);
+	    if output.is_empty() {
+		return Err(FritzError::Config("pass --output or set `system_cache_file_path`".to_string()));
+	    }
+	    build_cache(app_config, std::path::Path::new(output))?;
+	    print_cache_file_status(output);
+	},
+	CacheCommands::Export { json } => export_cache(app_config, *json)?,
+    }
+    Ok(())
 }
 
-fn get_progress_bar(app_config: &AppConfig, cli_args: &Cli) -> ProgressBar {
-    let mut num_steps = 1
+fn get_progress_bar(app_config: &AppConfig, _cli_args: &Cli) -> ProgressBar {
+    let num_steps = 1
 	+ app_config.hm_switch as u64
 	+ 2*app_config.commit_change as u64
 	+ app_config.push_change as u64;
-    let progress_bar = ProgressBar::new(num_steps).with_style(
-	indicatif::ProgressStyle::with_template("[{elapsed_precise}] {bar:40} {pos:>7}/{len:7} {wide_msg}").unwrap());
-    progress_bar
+    ProgressBar::new(num_steps).with_style(
+	indicatif::ProgressStyle::with_template("[{elapsed_precise}] {bar:40} {pos:>7}/{len:7} {wide_msg}").unwrap())
 }
 
-fn main() {
-    env_logger::init();
-    let cli_args = Cli::parse();
+fn run(cli_args: &Cli) -> Result<(), FritzError> {
     let config_file = match &cli_args.config {
 	Some(x) => x.clone(),
-	None => { get_default_config_file() }
+	None => { get_default_config_file()? }
     };
     info!("using config file: {}", config_file);
-    let app_config: AppConfig = Figment::new()
+    let mut app_config: AppConfig = Figment::new()
         .merge(Serialized::defaults(AppConfig::default()))
-        .merge(Toml::file(config_file))
+        .merge(Toml::file(&config_file))
         .merge(Env::prefixed("FRITZ_"))
-        .extract().unwrap();
+        .extract().map_err(|e| FritzError::Config(e.to_string()))?;
+    app_config.check_paths()?;
+    app_config.offline |= cli_args.offline;
+    app_config.config_file = config_file;
+    migrate_legacy_cache(&app_config);
 
-    let progress_bar = get_progress_bar(&app_config, &cli_args);
-    match cli_args.command {
-        Commands::Add {ref packages} => { add_packages(packages, &app_config, &cli_args, &progress_bar) },
-        Commands::Rm {ref packages} => { remove_packages(packages, &app_config, &cli_args, &progress_bar) },
-        Commands::Search { strings } => {
+    let progress_bar = get_progress_bar(&app_config, cli_args);
+    let result = match cli_args.command {
+        Commands::Add {ref packages} => { add_packages(packages, &app_config, cli_args, &progress_bar) },
+        Commands::Rm {ref packages} => { remove_packages(packages, &app_config, cli_args, &progress_bar) },
+        Commands::Search { ref strings, bin, ref system, installed, not_installed, ref namespace, top_level_only, ref license, no_unfree, no_broken } => {
             info!("running search");
-            let matching_results = search::search_cache(&strings, &app_config);
+            let filters = SearchFilters {
+                system: system.clone(),
+                installed: (installed || not_installed).then_some(installed),
+                namespace: namespace.clone(),
+                top_level_only,
+                license: license.clone(),
+                no_unfree,
+                no_broken,
+            };
+            let matching_results = if bin {
+                search::search_programs(strings, &filters, &app_config)?
+            } else {
+                search::search_cache(strings, &filters, &app_config)?
+            };
 	    for result in &matching_results[0..min(matching_results.len(),app_config.num_search_results)] {
 		pretty_print_result(result);
 	    }
+	    Ok(())
         },
+	Commands::Provides { ref program } => {
+	    info!("looking up providers of {}", program);
+	    for result in &search::find_providers(program, &app_config)? {
+		pretty_print_result(result);
+	    }
+	    Ok(())
+	},
 	Commands::List => {
 	    info!("listing fritz-managed packages");
-	    list_packages(&app_config);
+	    list_packages(&app_config)
+	},
+	Commands::Outdated { installed } => {
+	    info!("listing outdated packages");
+	    for package in outdated::find_outdated(&app_config, installed)? {
+		println!("{} \t {} -> {}", package.full_key, package.old_version, package.new_version);
+	    }
+	    Ok(())
+	},
+	Commands::Cache { ref command } => {
+	    run_cache_command(command, &app_config)
 	}
+    };
+    if result.is_err() {
+	progress_bar.abandon();
+    }
+    result
+}
+
+/// Search terms may start with `-`, so clap takes options given after the
+/// first term as terms too. Reject those rather than search for them.
+fn check_search_terms(strings: &[String]) -> Result<(), clap::Error> {
+    let mut command = Cli::command();
+    command.build();
+    let search = command.find_subcommand_mut("search").expect("search is a subcommand");
+    let option = strings.iter().find(|term| {
+	(term.starts_with("--") && *term != "--")
+	    || search.get_arguments().any(|arg| arg.get_short().is_some_and(|short| **term == format!("-{}", short)))
+    }).cloned();
+    match option {
+	Some(option) => Err(search.error(ErrorKind::UnknownArgument,
+	    format!("'{}' after a search term, put options before the search terms", option))),
+	None => Ok(()),
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let cli_args = Cli::parse();
+    if let Commands::Search { ref strings, .. } = cli_args.command {
+	check_search_terms(strings).unwrap_or_else(|e| e.exit());
+    }
+    if let Err(e) = run(&cli_args) {
+	eprintln!("error: {}", e);
+	eprintln!("hint: {}", e.hint());
+	std::process::exit(e.exit_code());
     }
 }
@@ -0,0 +1,160 @@
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use log::info;
+use indicatif::ProgressBar;
+
+use crate::cache::CacheEntry;
+use crate::index_source::{parse_streaming, run_nix_streaming};
+
+/// The parts of a package's `meta` attribute fritz keeps.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct PackageMeta {
+    /// SPDX ids where available, otherwise short names.
+    pub licenses: Vec<String>,
+    pub homepages: Vec<String>,
+    pub maintainers: Vec<String>,
+    pub main_program: Option<String>,
+    pub platforms: Vec<String>,
+    pub unfree: bool,
+    pub broken: bool,
+    pub insecure: bool,
+}
+
+// Attributes of `meta` passed through `nix eval`, everything else is dropped
+// to keep the output small.
+const META_ATTRS: &str = "{ license = null; homepage = null; maintainers = null; mainProgram = null; \
+                          platforms = null; broken = null; unfree = null; knownVulnerabilities = null; }";
+
+/// Values that may be a single item or a list of items, e.g. `meta.license`.
+fn one_or_many(value: Option<&Value>) -> Vec<&Value> {
+    match value {
+        Some(Value::Array(values)) => values.iter().collect(),
+        Some(Value::Null) | None => vec![],
+        Some(value) => vec![value],
+    }
+}
+
+fn license_name(license: &Value) -> Option<String> {
+    match license {
+        Value::String(name) => Some(name.clone()),
+        Value::Object(attrs) => ["spdxId", "shortName", "fullName"].iter()
+            .find_map(|attr| attrs.get(*attr).and_then(Value::as_str))
+            .map(str::to_string),
+        _ => None,
+    }
+}
+
+fn maintainer_name(maintainer: &Value) -> Option<String> {
+    match maintainer {
+        Value::String(name) => Some(name.clone()),
+        Value::Object(attrs) => ["github", "name", "email"].iter()
+            .find_map(|attr| attrs.get(*attr).and_then(Value::as_str))
+            .map(str::to_string),
+        _ => None,
+    }
+}
+
+impl PackageMeta {
+    /// Extract the interesting fields from a package's `meta` as JSON.
+    pub fn from_nix_meta(meta: &Value) -> PackageMeta {
+        let licenses = one_or_many(meta.get("license"));
+        let unfree = meta.get("unfree").and_then(Value::as_bool).unwrap_or(false)
+            || licenses.iter().any(|l| l.get("free").and_then(Value::as_bool) == Some(false));
+        PackageMeta {
+            licenses: licenses.iter().filter_map(|l| license_name(l)).collect(),
+            homepages: one_or_many(meta.get("homepage")).iter().filter_map(|h| h.as_str().map(str::to_string)).collect(),
+            maintainers: one_or_many(meta.get("maintainers")).iter().filter_map(|m| maintainer_name(m)).collect(),
+            main_program: meta.get("mainProgram").and_then(Value::as_str).map(str::to_string),
+            // platform patterns (attrsets) are skipped, only system doubles are kept
+            platforms: one_or_many(meta.get("platforms")).iter().filter_map(|p| p.as_str().map(str::to_string)).collect(),
+            unfree,
+            broken: meta.get("broken").and_then(Value::as_bool).unwrap_or(false),
+            insecure: meta.get("knownVulnerabilities").and_then(Value::as_array).is_some_and(|v| !v.is_empty()),
+        }
+    }
+}
+
+/// Read metadata from a local JSON file, either a map of attribute path to
+/// `meta`, or a nixpkgs `packages.json` (`{"packages": {attr: {"meta": ...}}}`).
+pub fn read_metadata_file(path: &str) -> Result<HashMap<String, PackageMeta>, String> {
+    let json = fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    let value: Value = serde_json::from_str(&json).map_err(|e| format!("failed to parse {}: {}", path, e))?;
+    let packages = match value.get("packages") {
+        Some(Value::Object(packages)) => packages,
+        _ => value.as_object().ok_or(format!("{} is not a JSON object", path))?,
+    };
+    Ok(packages.iter().map(|(attr, package)| {
+        let meta = package.get("meta").unwrap_or(package);
+        (attr.clone(), PackageMeta::from_nix_meta(meta))
+    }).collect())
+}
+
+/// Evaluate `meta` of the given attributes of `flake_ref`'s `legacyPackages.<system>`.
+pub fn eval_metadata(flake_ref: &str, system: &str, attrs: Vec<&String>, progress_bar: Option<&ProgressBar>) -> Result<HashMap<String, PackageMeta>, String> {
+    if let Some(pb) = progress_bar { pb.set_message(format!("evaluating metadata of {}", flake_ref)); }
+    // the attribute list is far too long for the command line, pass it through a file
+    let mut attrs_file = tempfile::Builder::new().prefix("fritz-attrs-").suffix(".json").tempfile()
+        .map_err(|e| format!("failed to create attribute list: {}", e))?;
+    serde_json::to_writer(&mut attrs_file, &attrs).map_err(|e| format!("failed to write attribute list: {}", e))?;
+    attrs_file.flush().map_err(|e| format!("failed to write attribute list: {}", e))?;
+    let attrs_path = attrs_file.path().to_str().ok_or("temporary directory is not valid UTF-8")?;
+    let expr = format!(r#"
+      {{ flakeRef, system, attrsFile }}:
+      let
+        pkgs = (builtins.getAttr system (builtins.getFlake flakeRef).legacyPackages);
+        attrs = builtins.fromJSON (builtins.readFile attrsFile);
+        meta = attr:
+          let
+            pkg = pkgs.lib.attrByPath (pkgs.lib.splitString "." attr) {{ }} pkgs;
+            kept = builtins.intersectAttrs {META_ATTRS} (pkg.meta or {{ }});
+            result = builtins.tryEval (builtins.deepSeq kept kept);
+          in if result.success then result.value else {{ }};
+      in builtins.listToAttrs (map (attr: {{ name = attr; value = meta attr; }}) attrs)
+    "#);
+    let args = ["eval", "--impure", "--json", "--expr", &expr,
+                "--argstr", "flakeRef", flake_ref, "--argstr", "system", system, "--argstr", "attrsFile", attrs_path];
+    let metas = run_nix_streaming(&args, |stdout| {
+        parse_streaming(stdout, progress_bar, |attr: String, meta: Value| Some((attr, PackageMeta::from_nix_meta(&meta))))
+    })?;
+    info!("evaluated metadata of {} packages", metas.len());
+    Ok(metas)
+}
+
+/// Attach metadata to the matching packages.
+pub fn enrich(packages: &mut HashMap<String, CacheEntry>, mut metas: HashMap<String, PackageMeta>) {
+    for (attr, entry) in packages.iter_mut() {
+        if let Some(meta) = metas.remove(attr) {
+            entry.meta = meta;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_nix_meta() {
+        let meta: Value = serde_json::from_str(r#"{
+          "license": [{ "spdxId": "MIT", "free": true }, { "shortName": "unfree", "free": false }],
+          "homepage": "https://example.org",
+          "maintainers": [{ "github": "alice", "name": "Alice" }, { "name": "Bob" }],
+          "mainProgram": "ex",
+          "platforms": ["x86_64-linux", { "kernel": { "name": "darwin" } }],
+          "knownVulnerabilities": ["CVE-2024-0001"]
+        }"#).unwrap();
+        let meta = PackageMeta::from_nix_meta(&meta);
+        assert_eq!(meta.licenses, ["MIT", "unfree"]);
+        assert_eq!(meta.homepages, ["https://example.org"]);
+        assert_eq!(meta.maintainers, ["alice", "Bob"]);
+        assert_eq!(meta.main_program.as_deref(), Some("ex"));
+        assert_eq!(meta.platforms, ["x86_64-linux"]);
+        assert!(meta.unfree && meta.insecure && !meta.broken);
+        let empty = PackageMeta::from_nix_meta(&Value::Null);
+        assert!(empty.licenses.is_empty() && !empty.unfree);
+    }
+}
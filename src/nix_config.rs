@@ -1,30 +1,20 @@
 use rnix::{self, SyntaxKind, SyntaxNode};
 use std::fs::{File,read_to_string};
 use std::io::{Write,stdin};
-use std::collections::HashSet;
-use nix_editor;
-use log::{info,error};
+use log::info;
 use itertools::Itertools;
 
 use crate::AppConfig;
-use crate::cache::{Cache,get_cache};
+use crate::cache::Cache;
+use crate::error::FritzError;
 
-pub fn get_nix_config(app_config: &AppConfig) -> NixConfig {
+pub fn get_nix_config(app_config: &AppConfig) -> Result<NixConfig, FritzError> {
     info!("reading config file: {}", app_config.package_config_file);
-    let cf_path = &app_config.package_config_file;
-    let current_packages = match get_current_packages(&app_config.package_config_file) {
-        Some(cfg) => {
-            cfg
-        },
-        None => {
-            eprintln!("Unable to read config file {}", app_config.package_config_file);
-            std::process::exit(1);
-        },
-    };
-    NixConfig {
+    let current_packages = get_current_packages(&app_config.package_config_file)?;
+    Ok(NixConfig {
         app_config: app_config.clone(),
         current_packages
-    }
+    })
 }
 
 pub struct NixConfig {
@@ -32,15 +22,23 @@ pub struct NixConfig {
     current_packages: SyntaxNode,
 }
 
-fn update_config_file(config_file_path: &String, new_str: &String) {
-    let mut config_file = match File::create(config_file_path) {
-        Ok(x) => x,
-        Err(e) => {
-            error!("Could not write config file {}: {}", config_file_path, e);
-            return
-        }
-    };
-    let _ = config_file.write_all(new_str.as_bytes());
+fn update_config_file(config_file_path: &String, new_str: &String) -> Result<(), FritzError> {
+    let write_error = |e: std::io::Error| FritzError::PackageConfig(format!("could not write {}: {}", config_file_path, e));
+    let mut config_file = File::create(config_file_path).map_err(write_error)?;
+    config_file.write_all(new_str.as_bytes()).map_err(write_error)
+}
+
+/// Ask whether to go on without the packages that were not found.
+fn confirm_not_found(not_found: Vec<String>) -> Result<(), FritzError> {
+    println!("Packages not found: {}", not_found.join(", "));
+    println!("Some packages were not found, continue? (Y/N): ");
+    let mut buffer = String::new();
+    // no answer (e.g. stdin closed) counts as no
+    let _ = stdin().read_line(&mut buffer);
+    if buffer.to_lowercase() != "y\n" {
+        return Err(FritzError::PackageNotFound(not_found))
+    }
+    Ok(())
 }
 
 fn config_contains_key(node: &SyntaxNode, item: &String) -> bool {
@@ -67,10 +65,7 @@ fn get_all_items_aux(node: &SyntaxNode) -> Option<Vec<String>> {
 	if child.kind() == SyntaxKind::NODE_LIST {
 	    let green = child.green().into_owned();
 	    let pkg_strings:Vec<String> = green.children().filter_map(|child| {
-		match child.as_node() {
-		    None => None,
-		    Some(node) => Some(node.to_string())
-		}
+		child.as_node().map(|node| node.to_string())
 	    }).collect();
 	    return Some(pkg_strings);
 	}
@@ -80,17 +75,18 @@ fn get_all_items_aux(node: &SyntaxNode) -> Option<Vec<String>> {
 
 impl NixConfig {
     fn get_full_package_name(&self, short_name: &String, cache: &Cache) -> Option<String> {
-        if cache.nixpkgs.contains_key(short_name) {
-            // exact match
-            Some(short_name.clone())
-        } else {
-            // try pkgs.{short_name}
-            let test_str = format!("pkgs.{}", short_name);
-            if cache.nixpkgs.contains_key(&test_str) {
-                info!("found full package name for '{}': {}", short_name, test_str);
-                Some(test_str)
-            } else {
-                error!("no full package name found for '{}'", short_name);
+        // exact match, e.g. `unstable.neovim`, or {default prefix}.{short_name}
+        match cache.lookup(short_name) {
+            Some(record) => {
+                info!("found full package name for '{}': {}", short_name, record.key);
+                let unavailable = cache.unavailable_on(&record);
+                if !unavailable.is_empty() {
+                    eprintln!("warning: {} is not available on {}", record.key, unavailable.join(", "));
+                }
+                Some(record.key)
+            },
+            None => {
+                info!("no full package name found for '{}'", short_name);
                 None
             }
         }
@@ -106,43 +102,35 @@ impl NixConfig {
                 info!("found full package name for '{}': {}", short_name, &test_str);
 		Some(test_str)
 	    } else {
-                error!("no full package name found for '{}'", short_name);
+                info!("no full package name found for '{}'", short_name);
 		None
 	    }
 	}
     }
 
-    pub fn add_packages(&self, packages: &Vec<String>, cache: &Cache, dry_run: bool) -> bool {
+    pub fn add_packages(&self, packages: &Vec<String>, cache: &Cache, dry_run: bool) -> Result<bool, FritzError> {
         info!("Trying to add package(s) {:?}", packages);
-        let full_package_set: Vec<String> = packages.iter().filter_map(
-            |short_name| {
-                self.get_full_package_name(short_name, cache)
-            }
-        ).collect();
+        let mut full_package_set = vec![];
+        let mut not_found_subset = vec![];
+        for short_name in packages {
+            match self.get_full_package_name(short_name, cache) {
+                Some(x) => { full_package_set.push(x) },
+                None => { not_found_subset.push(short_name.clone()) }
+            };
+        }
         // Ask to continue if not everything was found
-        //TODO: mention which packages were not found
-        if full_package_set.len() != packages.len() {
-            println!("Some packages were not found, continue? (Y/N): ");
-            let mut buffer = String::new();
-            stdin().read_line(&mut buffer).unwrap();
-            if buffer.to_lowercase() != "y\n" {
-                return false
-            }
+        if !not_found_subset.is_empty() {
+            confirm_not_found(not_found_subset)?;
         }
         let change_made = match Self::config_subset_not_present(&full_package_set, &self.current_packages) {
             Some(package_subset) => {
                 info!("adding subset: {:?}", &package_subset);
-                let new_str = match addtoarr_aux(&self.current_packages, package_subset) {
-                Some(new_str) => new_str,
-                    None => {
-                        eprintln!("error adding package");
-                        std::process::exit(1);
-                    }
-                };
+                let new_str = addtoarr_aux(&self.current_packages, package_subset).ok_or(
+                    FritzError::PackageConfig(format!("could not add packages to {}", self.app_config.package_config_file)))?;
                 info!("updating config file: {}", self.app_config.package_config_file);
                 // replace config with new_str, then commit with git.
                 if !dry_run {
-                    update_config_file(&self.app_config.package_config_file, &new_str.to_string());
+                    update_config_file(&self.app_config.package_config_file, &new_str.to_string())?;
 		    true
                 } else {
                     info!("dry run, not actually updating file");
@@ -154,7 +142,7 @@ impl NixConfig {
 		false
             }
         };
-	change_made
+	Ok(change_made)
     }
 
     fn get_package_subset_in_config(&self, packages: &Vec<String>) -> (Vec<String>,Vec<String>) {
@@ -169,36 +157,21 @@ impl NixConfig {
 	(found_subset, not_found_subset)
     }
 
-    pub fn remove_packages(&self, packages: &Vec<String>, dry_run: bool) -> bool {
+    pub fn remove_packages(&self, packages: &Vec<String>, dry_run: bool) -> Result<bool, FritzError> {
         println!("Trying to remove package(s) {:?}", packages);
 	let (full_package_set, not_found_subset) = self.get_package_subset_in_config(packages);
         // Ask to continue if not everything was found
-        if full_package_set.len() != packages.len() {
-	    println!("Packages not found: ");
-	    for ps in not_found_subset {
-		print!("{}, ", ps);
-	    }
-	    println!();
-            println!("Some packages were not found, continue? (Y/N): ");
-            let mut buffer = String::new();
-            stdin().read_line(&mut buffer).unwrap();
-            if buffer.to_lowercase() != "y\n" {
-                return false
-            }
+        if !not_found_subset.is_empty() {
+	    confirm_not_found(not_found_subset)?;
         }
-	let change_made = if packages.len() > 0 {
+	let change_made = if !packages.is_empty() {
 	    info!("removing subset: {:?}", &full_package_set);
-	    let new_str = match rmarr_aux(&self.current_packages, &full_package_set) {
-	    Some(new_str) => new_str,
-		None => {
-		    eprintln!("error removing package");
-		    std::process::exit(1);
-		}
-	    };
+	    let new_str = rmarr_aux(&self.current_packages, &full_package_set).ok_or(
+		FritzError::PackageConfig(format!("could not remove packages from {}", self.app_config.package_config_file)))?;
 	    info!("updating config file: {}", self.app_config.package_config_file);
 	    // replace config with new_str, then commit with git.
 	    if !dry_run {
-		update_config_file(&self.app_config.package_config_file, &new_str.to_string());
+		update_config_file(&self.app_config.package_config_file, &new_str.to_string())?;
 		true
 	    } else {
 		info!("dry run, not actually updating file");
@@ -208,15 +181,15 @@ impl NixConfig {
 	    info!("All packages already present");
 	    false
         };
-	change_made
+	Ok(change_made)
     }
 
-    fn config_subset_not_present(packages: &Vec<String>, config: &SyntaxNode) -> Option<Vec<String>> {
+    fn config_subset_not_present(packages: &[String], config: &SyntaxNode) -> Option<Vec<String>> {
         let config_str = config.to_string();
 
         let subset = packages.iter().unique().filter(|x| !config_str.contains(*x)).cloned().collect::<Vec<String>>();
         info!("returning subset: {:?}", subset);
-        if subset.len() > 0 {
+        if !subset.is_empty() {
             Some(subset)
         } else {
             None
@@ -224,7 +197,7 @@ impl NixConfig {
     }
 
     pub fn list_current_packages(&self) -> Option<Vec<String>> {
-	return get_all_items_aux(&self.current_packages);
+	get_all_items_aux(&self.current_packages)
     }
 }
 
@@ -265,13 +238,10 @@ fn addtoarr_aux(node: &SyntaxNode, items: Vec<String>) -> Option<SyntaxNode> {
                 }
             }
 
-            let index = match node.green().children().position(|x| match x.into_node() {
+            let index = node.green().children().position(|x| match x.into_node() {
                 Some(x) => x.to_owned() == child.green().into_owned(),
                 None => false,
-            }) {
-                Some(x) => x,
-                None => return None,
-            };
+            })?;
 
             let replace = node
                 .green()
@@ -295,7 +265,7 @@ fn rmarr_aux(node: &SyntaxNode, items: &Vec<String>) -> Option<SyntaxNode> {
             let mut idx = vec![];
             for elem in green.children() {
                 if elem.as_node().is_some() && items.contains(&elem.to_string()) {
-                    let index = match green.children().position(|x| match x.into_node() {
+                    let index = green.children().position(|x| match x.into_node() {
                         Some(x) => {
                             if let Some(y) = elem.as_node() {
                                 x.eq(y)
@@ -304,10 +274,7 @@ fn rmarr_aux(node: &SyntaxNode, items: &Vec<String>) -> Option<SyntaxNode> {
                             }
                         }
                         None => false,
-                    }) {
-                        Some(x) => x,
-                        None => return None,
-                    };
+                    })?;
                     idx.push(index)
                 }
             }
@@ -338,39 +305,15 @@ fn rmarr_aux(node: &SyntaxNode, items: &Vec<String>) -> Option<SyntaxNode> {
 }
 
 
-pub fn get_current_packages(config_file: &String) -> Option<SyntaxNode> {
-    // let content = fs::read_to_string(config_file)?;
-    let content = match read_to_string(config_file) {
-        Ok(content) => content,
-        Err(err) => {
-            eprintln!("error reading file: {}", err);
-            return None;
-        }
-    };
+pub fn get_current_packages(config_file: &String) -> Result<SyntaxNode, FritzError> {
+    let content = read_to_string(config_file)
+        .map_err(|e| FritzError::PackageConfig(format!("error reading {}: {}", config_file, e)))?;
     let parsed = rnix::Root::parse(&content);
-    let ast = rnix::Root::parse(config_file);
-    let sn = &ast.syntax();
-    let expr = match ast.tree().expr() {
-        Some(expr) => expr,
-        None => {
-            eprintln!("error reading file");
-            return None;
-        }
-    };
-
-    let configbase = match nix_editor::parse::getcfgbase(&parsed.syntax()) {
-        Some(x) => x,
-        None => {
-            eprintln!("could not parse {}", config_file);
-            return None;
-        }
-    };
-    let packages = match nix_editor::parse::findattr(&configbase, &"home.packages") {
-        Some(attr) => attr,
-        None => {
-            eprintln!("home.packages not found in config");
-            return None;
-        }
-    };
-    Some(packages)
+    if parsed.tree().expr().is_none() {
+        return Err(FritzError::PackageConfig(format!("{} is empty", config_file)));
+    }
+    let configbase = nix_editor::parse::getcfgbase(&parsed.syntax())
+        .ok_or(FritzError::PackageConfig(format!("could not parse {}", config_file)))?;
+    nix_editor::parse::findattr(&configbase, "home.packages")
+        .ok_or(FritzError::MissingHomePackages(config_file.clone()))
 }
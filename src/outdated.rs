@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::process::Command;
+use log::info;
+
+use crate::app_config::AppConfig;
+use crate::cache::get_cache;
+use crate::error::FritzError;
+use crate::nix_config::get_nix_config;
+
+/// A package in the package config file whose version changed.
+#[derive(Debug, Clone)]
+pub struct OutdatedPackage {
+    pub full_key: String,
+    pub old_version: String,
+    pub new_version: String,
+}
+
+/// Split a store path name like `ripgrep-14.1.0` into name and version, the
+/// way `builtins.parseDrvName` does: at the first `-` followed by a non-letter.
+fn parse_drv_name(name: &str) -> (&str, &str) {
+    let split = name.char_indices().find(|(i, c)| {
+        *c == '-' && name[i + 1..].chars().next().is_some_and(|next| !next.is_alphabetic())
+    });
+    match split {
+        Some((i, _)) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    }
+}
+
+/// Versions of the packages in the current home-manager generation, by name.
+pub fn installed_versions(app_config: &AppConfig) -> Result<HashMap<String, String>, FritzError> {
+    let output = Command::new(&app_config.switch_base_command).arg("packages").output()
+        .map_err(|e| FritzError::Generation(format!("failed to run {}: {}", app_config.switch_base_command, e)))?;
+    if !output.status.success() {
+        return Err(FritzError::Generation(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).lines()
+        .map(|line| parse_drv_name(line.trim()))
+        .filter(|(_, version)| !version.is_empty())
+        .map(|(name, version)| (name.to_string(), version.to_string()))
+        .collect())
+}
+
+/// Packages in the package config file whose version changed between the
+/// last two index snapshots, or with `installed`, between the installed
+/// home-manager generation and the index.
+pub fn find_outdated(app_config: &AppConfig, installed: bool) -> Result<Vec<OutdatedPackage>, FritzError> {
+    let nix_config = get_nix_config(app_config)?;
+    let cache = get_cache(app_config)?;
+    let installed_versions = if installed { Some(installed_versions(app_config)?) } else { None };
+    let mut outdated = vec![];
+    for name in nix_config.list_current_packages().unwrap_or_default() {
+        let Some(record) = cache.lookup(&name) else {
+            info!("'{}' is not in the index", name);
+            continue
+        };
+        let old_version = match &installed_versions {
+            Some(versions) => versions.get(&record.entry.pname).cloned(),
+            None => record.previous_version.clone(),
+        };
+        if let Some(old_version) = old_version {
+            if old_version != record.entry.version {
+                outdated.push(OutdatedPackage {
+                    full_key: record.key,
+                    old_version,
+                    new_version: record.entry.version,
+                });
+            }
+        }
+    }
+    Ok(outdated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_drv_name;
+
+    #[test]
+    fn splits_like_parse_drv_name() {
+        assert_eq!(parse_drv_name("ripgrep-14.1.0"), ("ripgrep", "14.1.0"));
+        assert_eq!(parse_drv_name("python3.11-requests-2.31.0"), ("python3.11-requests", "2.31.0"));
+        assert_eq!(parse_drv_name("nix-index-unstable-2023-01-01"), ("nix-index-unstable", "2023-01-01"));
+        assert_eq!(parse_drv_name("hm-session-vars.sh"), ("hm-session-vars.sh", ""));
+        assert_eq!(parse_drv_name("font-0xproto-2.0"), ("font", "0xproto-2.0"));
+    }
+}
@@ -0,0 +1,99 @@
+use std::io::Read;
+use log::{info,warn};
+use platform_info::{PlatformInfo,PlatformInfoAPI,UNameAPI};
+
+use crate::app_config::AppConfig;
+use crate::index_source::run_nix_streaming;
+
+/// Nix system doubles fritz knows how to index.
+const NIX_SYSTEMS: &[&str] = &[
+    "x86_64-linux",
+    "aarch64-linux",
+    "i686-linux",
+    "armv6l-linux",
+    "armv7l-linux",
+    "riscv64-linux",
+    "powerpc64le-linux",
+    "x86_64-darwin",
+    "aarch64-darwin",
+    "x86_64-freebsd",
+];
+
+/// Map `uname -m` / `uname -s` output to a nix system double, e.g. `x86_64-linux`.
+fn nix_system_from_uname(machine: &str, sysname: &str) -> Option<String> {
+    let arch = match &machine.to_lowercase()[..] {
+        "x86_64" | "amd64" => "x86_64",
+        "aarch64" | "arm64" => "aarch64",
+        "i386" | "i486" | "i586" | "i686" => "i686",
+        "armv6l" => "armv6l",
+        "armv7l" | "armv8l" => "armv7l",
+        "riscv64" => "riscv64",
+        "ppc64le" | "powerpc64le" => "powerpc64le",
+        _ => return None,
+    };
+    let kernel = match &sysname.to_lowercase()[..] {
+        "linux" => "linux",
+        "darwin" => "darwin",
+        "freebsd" => "freebsd",
+        _ => return None,
+    };
+    let system = format!("{}-{}", arch, kernel);
+    if NIX_SYSTEMS.contains(&&system[..]) {
+        Some(system)
+    } else {
+        None
+    }
+}
+
+/// `builtins.currentSystem`, asked from nix.
+fn nix_system_from_nix() -> Result<String, String> {
+    let system = run_nix_streaming(&["eval", "--impure", "--raw", "--expr", "builtins.currentSystem"], |mut stdout| {
+        let mut system = String::new();
+        stdout.read_to_string(&mut system).map_err(|e| e.to_string())?;
+        Ok(system.trim().to_string())
+    })?;
+    if system.is_empty() { Err("nix returned no system".to_string()) } else { Ok(system) }
+}
+
+/// The nix system to index packages for: the `system` option if set,
+/// otherwise detected from uname, otherwise asked from nix.
+pub fn get_nix_system(config: &AppConfig) -> Result<String, String> {
+    if !config.system.is_empty() {
+        return Ok(config.system.clone());
+    }
+    match PlatformInfo::new() {
+        Ok(info) => {
+            let machine = info.machine().to_string_lossy();
+            let sysname = info.sysname().to_string_lossy();
+            if let Some(system) = nix_system_from_uname(&machine, &sysname) {
+                info!("detected nix system {}", system);
+                return Ok(system);
+            }
+            warn!("unknown platform {} {}, asking nix", machine, sysname);
+        },
+        Err(e) => {
+            warn!("unable to determine platform: {}, asking nix", e);
+        }
+    }
+    nix_system_from_nix().map_err(|e| {
+        format!("unable to determine nix system ({}), set the `system` option (e.g. system = \"x86_64-linux\")", e)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::nix_system_from_uname;
+
+    #[test]
+    fn maps_uname_to_nix_systems() {
+        assert_eq!(nix_system_from_uname("x86_64", "Linux").as_deref(), Some("x86_64-linux"));
+        assert_eq!(nix_system_from_uname("arm64", "Darwin").as_deref(), Some("aarch64-darwin"));
+        assert_eq!(nix_system_from_uname("amd64", "FreeBSD").as_deref(), Some("x86_64-freebsd"));
+        assert_eq!(nix_system_from_uname("armv8l", "Linux").as_deref(), Some("armv7l-linux"));
+        assert_eq!(nix_system_from_uname("ppc64le", "Linux").as_deref(), Some("powerpc64le-linux"));
+        // real architectures and kernels nix has no system for
+        assert_eq!(nix_system_from_uname("arm64", "FreeBSD"), None);
+        assert_eq!(nix_system_from_uname("sparc64", "Linux"), None);
+        assert_eq!(nix_system_from_uname("x86_64", "SunOS"), None);
+    }
+}
@@ -0,0 +1,16 @@
+use rusqlite::{Connection, OpenFlags};
+use log::info;
+
+/// Read (program, attribute path) pairs for `system` from a `programs.sqlite`
+/// database as shipped with nixpkgs channels.
+pub fn read_programs_db(path: &str, system: &str) -> Result<Vec<(String, String)>, String> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("failed to open {}: {}", path, e))?;
+    let mut statement = connection.prepare("SELECT name, package FROM Programs WHERE system = ?1")
+        .map_err(|e| format!("failed to read {}: {}", path, e))?;
+    let programs = statement.query_map([system], |row| Ok((row.get(0)?, row.get(1)?)))
+        .and_then(|rows| rows.collect::<Result<Vec<(String, String)>, _>>())
+        .map_err(|e| format!("failed to read {}: {}", path, e))?;
+    info!("read {} programs from {}", programs.len(), path);
+    Ok(programs)
+}
@@ -1,68 +1,611 @@
 use serde::Deserialize;
 use serde::Serialize;
-use std::collections::HashMap;
-use std::path::Path;
-use std::time::SystemTime;
-use std::process::Command;
-use duration_string::DurationString;
-use log::{info,error};
+use log::{info, warn};
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 use crate::app_config::AppConfig;
-use crate::cache::{Cache,CacheEntry, get_cache};
+use crate::cache::{Cache, CacheRecord, FieldLengths, get_cache};
+use crate::error::FritzError;
+use crate::nix_config::get_nix_config;
+use crate::outdated::installed_versions;
+use crate::cache_file::tokenize;
+use crate::fuzzy::{edit_distance, max_typos};
+
+// Relevance is BM25F: each search term's matches in the attribute path,
+// pname and description are weighted, normalised by the field's length and
+// saturated, then scaled by how rare the term is in the index.
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+const ATTR_PATH_WEIGHT: f32 = 2.0;
+const PNAME_WEIGHT: f32 = 1.5;
+const DESCRIPTION_WEIGHT: f32 = 1.0;
+/// How much a term counts when it starts a word, is part of a word or is a
+/// word with a typo (per typo), compared to being a whole word. Only counted
+/// for packages without the whole word.
+const PREFIX_MATCH: f32 = 0.5;
+const SUBSTRING_MATCH: f32 = 0.25;
+const FUZZY_MATCH: f32 = 0.3;
+/// Multiplies the score once per term that is the package name, for
+/// packages outside of nested sets only.
+const EXACT_NAME_BOOST: f32 = 3.0;
+/// Multiplies the score of packages in nested sets such as `python3Packages`.
+const NESTED_WEIGHT: f32 = 0.5;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SearchResult {
     pub full_key: String,
+    pub source: String,
     pub description: String,
     pub pname: String,
     pub version: String,
-    pub desc_score: f32,
-    pub key_score: f32,
+    pub score: f32,
+    pub installed: Installed,
 }
 
-pub fn search_cache(strings: &Vec<String>, config: &AppConfig) -> Vec<SearchResult> {
-    let cache = get_cache(&config).unwrap();
-    let mut matching_results: Vec<SearchResult> = cache.package_iter().filter_map(|(key, result)| score_result(key, result, &strings)).collect();
-    matching_results.sort_by(|a, b| b.desc_score.partial_cmp(&a.desc_score).unwrap());
-    matching_results.sort_by(|a, b| b.key_score.partial_cmp(&a.key_score).unwrap());
-    info!("{} matching results", matching_results.len());
-    // info!("top result: {:?}", matching_results[0]);
-    matching_results
+/// Whether a search result is installed already.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum Installed {
+    #[default]
+    No,
+    /// In the package config file.
+    Config,
+    /// In the home-manager generation, e.g. from another module.
+    HomeManager,
 }
 
-fn score_result(key: &String, result: &CacheEntry, search_strings: &Vec<String>) -> Option<SearchResult> {
-    let mut desc_term_freq = 0;
-    let mut key_term_freq = 0;
-    for string in search_strings {
-        if result.description.to_lowercase().contains(string) {
-            desc_term_freq += string.len();
+/// Restrictions on which packages a search returns.
+#[derive(Debug, Default, Clone)]
+pub struct SearchFilters {
+    /// Only packages available on this system.
+    pub system: Option<String>,
+    /// Only packages that are (true) or are not (false) in the package config file.
+    pub installed: Option<bool>,
+    /// Only packages in this package set, e.g. `python3Packages`.
+    pub namespace: Option<String>,
+    /// Only packages outside of nested package sets.
+    pub top_level_only: bool,
+    /// Only packages with a license containing this, e.g. `MIT` or `gpl`.
+    pub license: Option<String>,
+    pub no_unfree: bool,
+    pub no_broken: bool,
+}
+
+impl SearchFilters {
+    /// Keys of the packages in the package config file. A config file that
+    /// can not be read is only an error when filtering on installed state.
+    fn installed_keys(&self, cache: &Cache, config: &AppConfig) -> Result<HashSet<String>, FritzError> {
+        let packages = get_nix_config(config).and_then(|nix_config| {
+            nix_config.list_current_packages().ok_or_else(|| {
+                FritzError::PackageConfig(format!("could not read home.packages in {}", config.package_config_file))
+            })
+        });
+        match packages {
+            Ok(packages) => Ok(packages.iter()
+                .filter_map(|name| cache.lookup(name))
+                .map(|record| record.key)
+                .collect()),
+            Err(e) if self.installed.is_none() => {
+                info!("not marking installed packages: {}", e);
+                Ok(HashSet::new())
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    fn accepts(&self, cache: &Cache, record: &CacheRecord, installed_keys: &HashSet<String>) -> bool {
+        let meta = &record.entry.meta;
+        self.system.as_ref().is_none_or(|system| cache.available_on(record, system))
+            && self.installed.is_none_or(|installed| installed_keys.contains(&record.key) == installed)
+            && self.namespace.as_ref().is_none_or(|namespace| {
+                record.attr_path().strip_prefix(&namespace[..]).is_some_and(|rest| rest.starts_with('.'))
+            })
+            && !(self.top_level_only && record.attr_path().contains('.'))
+            && self.license.as_ref().is_none_or(|license| {
+                let license = license.to_lowercase();
+                meta.licenses.iter().any(|l| l.to_lowercase().contains(&license))
+            })
+            && !(self.no_unfree && meta.unfree)
+            && !(self.no_broken && meta.broken)
+    }
+
+    fn check(&self, cache: &Cache) {
+        if let Some(system) = &self.system {
+            if !cache.header.systems.contains(system) {
+                eprintln!("warning: {} is not indexed, add it to `target_systems`", system);
+            }
+        }
+        let filters_metadata = self.license.is_some() || self.no_unfree || self.no_broken;
+        if filters_metadata && !cache.header.sources.iter().any(|source| source.metadata) {
+            eprintln!("warning: the index has no package metadata, set `enrich_metadata` to filter on it");
         }
-        if key.to_lowercase().contains(string) {
-            key_term_freq += string.len();
+    }
+}
+
+impl SearchResult {
+    fn from_record(record: &CacheRecord, score: f32) -> SearchResult {
+        SearchResult {
+            full_key: record.key.clone(),
+            source: record.source.clone(),
+            description: record.entry.description.clone(),
+            pname: record.entry.pname.clone(),
+            version: record.entry.version.clone(),
+            score,
+            installed: Installed::No,
         }
     }
-    if desc_term_freq + key_term_freq > 0 {
-        let desc_score = if desc_term_freq > 0 && result.description.len() > 0 {
-            (desc_term_freq as f32) / (result.description.len() as f32)
+}
+
+/// Mark the results in `installed_keys` and, with `mark_home_manager_packages`,
+/// the ones in the home-manager generation as installed.
+fn mark_installed(results: &mut [SearchResult], installed_keys: &HashSet<String>, config: &AppConfig) {
+    let generation = match config.mark_home_manager_packages {
+        true => installed_versions(config).unwrap_or_else(|e| {
+            warn!("not marking home-manager packages: {}", e);
+            Default::default()
+        }),
+        false => Default::default(),
+    };
+    for result in results {
+        result.installed = if installed_keys.contains(&result.full_key) {
+            Installed::Config
+        } else if generation.contains_key(&result.pname) {
+            Installed::HomeManager
         } else {
-            0.0
+            Installed::No
         };
-        let key_score = if key_term_freq > 0 && result.pname.len() > 0 {
-            (key_term_freq as f32) / (key.len() as f32)
+    }
+}
+
+/// The part of a package a query clause is matched against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    /// Words of the attribute path, pname or description, with typos.
+    Any,
+    Pname,
+    Description,
+    /// The attribute path, with or without the source prefix.
+    Key,
+    Version,
+}
+
+/// One clause of a search query, e.g. `-python`, `"language server"` or `version:^1.`.
+#[derive(Debug, Clone)]
+struct Clause {
+    field: Field,
+    /// Lowercase text to look for.
+    text: String,
+    /// Words of `text`, which `Field::Any` clauses match in order.
+    words: Vec<String>,
+    /// Written with `^`: the field has to start with `text`.
+    anchored: bool,
+    /// Written with `-`: packages matching the clause are excluded.
+    negated: bool,
+}
+
+/// A parsed search query. A package matches if it matches all clauses of
+/// any group, groups are separated by `OR`.
+#[derive(Debug)]
+struct Query {
+    groups: Vec<Vec<Clause>>,
+}
+
+impl Query {
+    /// Parse search strings like `"language server" -python pname:lsp version:^1.`.
+    /// A string with spaces, i.e. one quoted in the shell, is a phrase.
+    fn parse(strings: &[String]) -> Query {
+        let mut groups = vec![vec![]];
+        for raw in strings.iter().flat_map(|string| split_query(string)) {
+            if raw == "OR" {
+                groups.push(vec![]);
+                continue;
+            }
+            if raw == "AND" {
+                continue;
+            }
+            let (negated, raw) = match raw.strip_prefix('-') {
+                Some(rest) if !rest.is_empty() => (true, rest),
+                _ => (false, &raw[..]),
+            };
+            let (field, raw) = match raw.split_once(':') {
+                Some(("pname", rest)) => (Field::Pname, rest),
+                Some(("desc", rest)) => (Field::Description, rest),
+                Some(("key", rest)) => (Field::Key, rest),
+                Some(("version", rest)) => (Field::Version, rest),
+                _ => (Field::Any, raw),
+            };
+            let (anchored, raw) = match raw.strip_prefix('^') {
+                Some(rest) if field != Field::Any => (true, rest),
+                _ => (false, raw),
+            };
+            let text = raw.trim_matches('"').to_lowercase();
+            let words: Vec<String> = tokenize(&text).collect();
+            if text.is_empty() || (field == Field::Any && words.is_empty()) {
+                continue;
+            }
+            groups.last_mut().unwrap().push(Clause { field, text, words, anchored, negated });
+        }
+        groups.retain(|group| !group.is_empty());
+        if groups.is_empty() {
+            // nothing to look for, e.g. only filters: everything matches
+            groups.push(vec![]);
+        }
+        Query { groups }
+    }
+
+    /// Words whose matches count towards the relevance score.
+    fn scored_words(&self) -> BTreeSet<&str> {
+        self.groups.iter().flatten()
+            .filter(|clause| clause.field == Field::Any && !clause.negated)
+            .flat_map(|clause| clause.words.iter().map(|word| &word[..]))
+            .collect()
+    }
+
+    /// Ids of the records that may match, or None if every record may.
+    /// `known` holds candidates already looked up, by text.
+    fn candidates(&self, cache: &Cache, known: &HashMap<&str, BTreeSet<u32>>) -> Option<BTreeSet<u32>> {
+        let mut candidates = BTreeSet::new();
+        for group in &self.groups {
+            // version is not indexed, and excluding clauses do not narrow the search
+            let group_candidates = group.iter()
+                .filter(|clause| clause.field != Field::Version && !clause.negated && !clause.needs_scan())
+                .filter_map(|clause| known.get(&clause.text[..]).cloned().or_else(|| cache.candidates(&clause.text)))
+                .reduce(|a, b| a.intersection(&b).copied().collect())?;
+            candidates.extend(group_candidates);
+        }
+        Some(candidates)
+    }
+}
+
+/// Split a search string at spaces outside of double quotes.
+fn split_query(string: &str) -> Vec<String> {
+    if string.contains(char::is_whitespace) && !string.contains('"') {
+        return vec![string.to_string()];
+    }
+    let mut parts = vec![];
+    let mut part = String::new();
+    let mut quoted = false;
+    for c in string.chars() {
+        match c {
+            '"' => { quoted = !quoted; part.push(c); },
+            c if c.is_whitespace() && !quoted => parts.extend((!part.is_empty()).then(|| std::mem::take(&mut part))),
+            c => part.push(c),
+        }
+    }
+    parts.extend((!part.is_empty()).then_some(part));
+    parts
+}
+
+/// A package's searched fields, split into lowercase words.
+struct RecordWords {
+    attr_path: Vec<String>,
+    pname: Vec<String>,
+    description: Vec<String>,
+}
+
+impl Clause {
+    /// Whether field terms, which match anywhere in a field, have parts too
+    /// short for the trigram index, which only finds those at word starts.
+    fn needs_scan(&self) -> bool {
+        self.field != Field::Any && !self.anchored
+            && tokenize(&self.text).any(|part| part.chars().count() < 3)
+    }
+
+    fn matches(&self, record: &CacheRecord, words: &RecordWords) -> bool {
+        let text_matches = |field: &str| {
+            let field = field.to_lowercase();
+            if self.anchored { field.starts_with(&self.text) } else { field.contains(&self.text) }
+        };
+        match self.field {
+            Field::Any => match &self.words[..] {
+                [word] => [&words.attr_path, &words.pname, &words.description].iter().enumerate()
+                    // typos are fine in what to look for, not in what to exclude
+                    .any(|(i, field)| term_frequency(word, field, i < 2 && !self.negated) > 0.0),
+                phrase => [&words.attr_path, &words.pname, &words.description].iter()
+                    .any(|field| field.windows(phrase.len()).any(|window| window == phrase)),
+            },
+            Field::Pname => text_matches(&record.entry.pname),
+            Field::Description => text_matches(&record.entry.description),
+            Field::Key => text_matches(record.attr_path()) || text_matches(&record.key),
+            Field::Version => text_matches(&record.entry.version),
+        }
+    }
+}
+
+pub fn search_cache(strings: &[String], filters: &SearchFilters, config: &AppConfig) -> Result<Vec<SearchResult>, FritzError> {
+    let cache = get_cache(config)?;
+    filters.check(&cache);
+    let installed_keys = filters.installed_keys(&cache, config)?;
+    let query = Query::parse(strings);
+    info!("search query: {:?}", query);
+    let mut matching_results = rank(&cache, &query, filters, &installed_keys);
+    mark_installed(&mut matching_results, &installed_keys, config);
+    info!("{} matching results", matching_results.len());
+    Ok(matching_results)
+}
+
+/// The packages matching `query` and `filters`, most relevant first.
+fn rank(cache: &Cache, query: &Query, filters: &SearchFilters, installed_keys: &HashSet<String>) -> Vec<SearchResult> {
+    let num_records = cache.len() as f32;
+    // look up the candidates of each word once
+    let word_candidates: HashMap<&str, BTreeSet<u32>> = query.scored_words().into_iter()
+        .filter_map(|word| Some((word, cache.candidates(word)?)))
+        .collect();
+    // how rare each scored word is in the index
+    let idfs: Vec<(&str, f32)> = query.scored_words().into_iter().map(|word| {
+        let matches = word_candidates.get(word).map_or(num_records, |ids| ids.len() as f32);
+        (word, (1.0 + (num_records - matches + 0.5) / (matches + 0.5)).ln())
+    }).collect();
+    let records: Box<dyn Iterator<Item = CacheRecord>> = match query.candidates(cache, &word_candidates) {
+        Some(ids) => Box::new(ids.into_iter().filter_map(|id| cache.record(id as usize))),
+        None => Box::new(cache.package_iter()),
+    };
+    let mut matching_results: Vec<SearchResult> = records
+        .filter(|record| filters.accepts(cache, record, installed_keys))
+        .filter_map(|record| score_result(&record, query, &idfs, &cache.header.field_lengths))
+        .collect();
+    matching_results.sort_by(|a, b| b.score.total_cmp(&a.score));
+    matching_results
+}
+
+/// How often `term` occurs in the words of `text`, counting partial and,
+/// if `fuzzy`, typo matches as a fraction of an occurrence.
+fn term_frequency(term: &str, words: &[String], fuzzy: bool) -> f32 {
+    let typos = if fuzzy { max_typos(term.chars().count()) } else { 0 };
+    words.iter().map(|word| {
+        if word == term {
+            1.0
+        } else if word.starts_with(term) {
+            PREFIX_MATCH
+        } else if word.contains(term) {
+            SUBSTRING_MATCH
+        } else if typos > 0 {
+            edit_distance(term, word, typos).map_or(0.0, |typos| FUZZY_MATCH / typos as f32)
         } else {
             0.0
-        };
-        Some(SearchResult{
-            full_key: key.clone(),
-            description: result.description.clone(),
-            pname: result.pname.clone(),
-            version: result.version.clone(),
-            desc_score,
-            key_score})
-    } else {
-        None
+        }
+    }).sum()
+}
+
+/// Scores a package by the BM25F relevance of the query's words, see the
+/// constants above. Returns None if the package does not match the query.
+fn score_result(record: &CacheRecord, query: &Query, idfs: &[(&str, f32)], field_lengths: &FieldLengths) -> Option<SearchResult> {
+    let attr_path = record.attr_path();
+    let pname = record.entry.pname.to_lowercase();
+    let words = RecordWords {
+        attr_path: tokenize(attr_path).collect(),
+        pname: tokenize(&pname).collect(),
+        description: tokenize(&record.entry.description).collect(),
+    };
+    let matched = query.groups.iter()
+        .any(|group| group.iter().all(|clause| clause.matches(record, &words) != clause.negated));
+    if !matched {
+        return None;
+    }
+    let fields = [
+        (&words.attr_path, ATTR_PATH_WEIGHT, field_lengths.attr_path, true),
+        (&words.pname, PNAME_WEIGHT, field_lengths.pname, true),
+        (&words.description, DESCRIPTION_WEIGHT, field_lengths.description, false),
+    ];
+    // the attribute name without the package set, e.g. `requests`
+    let attr_name = attr_path.rsplit('.').next().unwrap_or(attr_path).to_lowercase();
+    // packages matched by field clauses alone are all equally relevant
+    let mut score = if idfs.is_empty() { 1.0 } else { 0.0 };
+    // `perlPackages.JSON` is rarely what a search for `json` is after
+    let nested = attr_path.contains('.');
+    let mut boost = if nested { NESTED_WEIGHT } else { 1.0 };
+    for (word, idf) in idfs {
+        // a package with the word itself is what was searched for, words it
+        // is part of (`jsonnet` for `json`) only count for packages without it
+        let exact = fields.iter().any(|(words, ..)| words.iter().any(|w| w == word));
+        let weighted_frequency: f32 = fields.iter().map(|(words, weight, average_length, fuzzy)| {
+            let length_norm = 1.0 - B + B * words.len() as f32 / average_length.max(1.0);
+            let frequency = match exact {
+                true => words.iter().filter(|w| w == word).count() as f32,
+                false => term_frequency(word, words, *fuzzy),
+            };
+            weight * frequency / length_norm
+        }).sum();
+        score += idf * weighted_frequency * (K1 + 1.0) / (K1 + weighted_frequency);
+        if !nested && (pname == *word || attr_name == *word) {
+            boost *= EXACT_NAME_BOOST;
+        }
+    }
+    Some(SearchResult::from_record(record, score * boost))
+}
+
+/// Search executable names instead of package names and descriptions.
+/// Exact program names rank first, then shorter names containing a search string.
+pub fn search_programs(strings: &[String], filters: &SearchFilters, config: &AppConfig) -> Result<Vec<SearchResult>, FritzError> {
+    let cache = get_cache(config)?;
+    filters.check(&cache);
+    let installed_keys = filters.installed_keys(&cache, config)?;
+    let mut matching_results = vec![];
+    for (program, ids) in cache.programs() {
+        let program_lower = program.to_lowercase();
+        let score: f32 = strings.iter()
+            .filter(|string| program_lower.contains(&string.to_lowercase()))
+            .map(|string| string.len() as f32 / program.len() as f32)
+            .sum();
+        if score > 0.0 {
+            matching_results.extend(ids.iter()
+                .filter_map(|id| cache.record(*id as usize))
+                .filter(|record| filters.accepts(&cache, record, &installed_keys))
+                .map(|record| SearchResult::from_record(&record, score)));
+        }
+    }
+    matching_results.sort_by(|a, b| b.score.total_cmp(&a.score));
+    mark_installed(&mut matching_results, &installed_keys, config);
+    info!("{} matching results", matching_results.len());
+    Ok(matching_results)
+}
+
+/// Packages providing an executable named exactly `program`.
+pub fn find_providers(program: &str, config: &AppConfig) -> Result<Vec<SearchResult>, FritzError> {
+    let cache = get_cache(config)?;
+    let mut providers: Vec<SearchResult> = cache.programs().get(program).map_or(vec![], |ids| {
+        ids.iter()
+            .filter_map(|id| cache.record(*id as usize))
+            .map(|record| SearchResult::from_record(&record, 1.0))
+            .collect()
+    });
+    if providers.is_empty() {
+        let programs_indexed = config.index_sources().iter().any(|source| !source.programs_db.is_empty());
+        return Err(FritzError::ProgramNotFound { program: program.to_string(), programs_indexed });
     }
+    let installed_keys = SearchFilters::default().installed_keys(&cache, config)?;
+    mark_installed(&mut providers, &installed_keys, config);
+    Ok(providers)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_file::tests::{entry, open_cache};
 
+    fn json_cache() -> (tempfile::TempDir, Cache) {
+        open_cache(vec![
+            ("jq", entry("jq", "Lightweight and flexible command-line JSON processor")),
+            ("json-c", entry("json-c", "JSON implementation in C")),
+            ("jsonnet", entry("jsonnet", "Purely-functional configuration language that helps you define JSON data")),
+            ("jsoncpp", entry("jsoncpp", "C++ library for interacting with JSON")),
+            ("json-glib", entry("json-glib", "Library providing (de)serialization support for the JavaScript Object Notation (JSON) format")),
+            ("haskellPackages.json-alt", entry("json-alt", "Union 'alternative' or Either that has untagged JSON encoding")),
+            ("python3Packages.jsonschema", entry("jsonschema", "Implementation of JSON Schema validation for Python")),
+            ("python3Packages.jsonpickle", entry("jsonpickle", "Python library for serializing any arbitrary object graph into JSON")),
+            ("nodePackages.json-server", entry("json-server", "Get a full fake REST API with zero coding in less than 30 seconds")),
+            ("perlPackages.JSON", entry("JSON", "JSON (JavaScript Object Notation) encoder/decoder")),
+            ("fx", entry("fx", "Terminal JSON viewer")),
+            ("gron", entry("gron", "Make JSON greppable")),
+            ("yj", entry("yj", "Convert YAML <=> TOML <=> JSON <=> HCL")),
+            ("python3Packages.jq", entry("jq", "Python bindings for jq")),
+            ("vim", entry("vim", "The most popular clone of the VI editor")),
+        ])
+    }
+
+    fn search(cache: &Cache, strings: &[&str], filters: &SearchFilters) -> Vec<String> {
+        let strings: Vec<String> = strings.iter().map(|s| s.to_string()).collect();
+        rank(cache, &Query::parse(&strings), filters, &HashSet::new()).into_iter().map(|result| result.full_key).collect()
+    }
+
+    fn parse(strings: &[&str]) -> Query {
+        Query::parse(&strings.iter().map(|s| s.to_string()).collect::<Vec<String>>())
+    }
+
+    #[test]
+    fn splits_at_spaces_outside_quotes() {
+        assert_eq!(split_query(r#""language server" -python"#), [r#""language server""#, "-python"]);
+        assert_eq!(split_query("pname:lsp"), ["pname:lsp"]);
+        // a string with spaces but no quotes was quoted in the shell
+        assert_eq!(split_query("language server"), ["language server"]);
+        assert!(split_query("").is_empty());
+    }
+
+    #[test]
+    fn parses_clauses() {
+        let query = parse(&[r#""Language Server""#, "-python", "pname:^lsp", "version:1.", "AND", "-"]);
+        assert_eq!(query.groups.len(), 1);
+        let clauses = &query.groups[0];
+        assert_eq!(clauses.len(), 4);
+        assert_eq!((clauses[0].field, &clauses[0].words[..]), (Field::Any, &["language".to_string(), "server".to_string()][..]));
+        assert!(clauses[1].negated && clauses[1].text == "python");
+        assert!(clauses[2].field == Field::Pname && clauses[2].anchored && clauses[2].text == "lsp");
+        assert!(clauses[3].field == Field::Version && !clauses[3].anchored && clauses[3].text == "1.");
+        assert_eq!(query.scored_words().into_iter().collect::<Vec<_>>(), ["language", "server"]);
+    }
 
+    #[test]
+    fn parses_or_groups() {
+        let query = parse(&["vim", "OR", "emacs", "gtk", "OR"]);
+        assert_eq!(query.groups.len(), 2);
+        assert_eq!(query.groups[1].len(), 2);
+        // only filters: everything matches
+        assert_eq!(parse(&[]).groups.len(), 1);
+        assert!(parse(&["--"]).groups[0].is_empty());
+    }
+
+    #[test]
+    fn matches_queries() {
+        let (_dir, cache) = json_cache();
+        let filters = SearchFilters::default();
+        assert_eq!(search(&cache, &["json", "-perl", "-haskell", "-python", "-node"], &filters).len(), 8);
+        let mut top_level = search(&cache, &["pname:^json", "-key:packages."], &filters);
+        top_level.sort();
+        assert_eq!(top_level, ["pkgs.json-c", "pkgs.json-glib", "pkgs.jsoncpp", "pkgs.jsonnet"]);
+        assert_eq!(search(&cache, &[r#""command line json""#], &filters), ["pkgs.jq"]);
+        assert_eq!(search(&cache, &["jq", "OR", "fx"], &filters).len(), 3);
+        // field terms shorter than a trigram match inside words too
+        assert_eq!(search(&cache, &["pname:pi"], &filters), ["pkgs.python3Packages.jsonpickle"]);
+        assert_eq!(search(&cache, &["key:pi"], &filters), ["pkgs.python3Packages.jsonpickle"]);
+    }
+
+    #[test]
+    fn ranks_packages_with_the_word_above_longer_words() {
+        let (_dir, cache) = json_cache();
+        let results = search(&cache, &["json"], &SearchFilters::default());
+        let position = |key: &str| results.iter().position(|result| result == key).unwrap();
+        // top level packages with the word, then nested ones, even named after it
+        let with_word = ["pkgs.jq", "pkgs.json-c", "pkgs.json-glib", "pkgs.jsoncpp", "pkgs.fx", "pkgs.gron", "pkgs.yj"];
+        let mut top_level: Vec<&String> = results[..with_word.len()].iter().collect();
+        top_level.sort();
+        let mut expected = with_word.to_vec();
+        expected.sort();
+        assert_eq!(top_level, expected, "{:?}", results);
+        assert!(position("pkgs.jq") < position("pkgs.perlPackages.JSON"), "{:?}", results);
+        // above those it is only part of the name of
+        for longer in ["pkgs.jsonnet", "pkgs.python3Packages.jsonschema", "pkgs.python3Packages.jsonpickle"] {
+            assert!(position("pkgs.perlPackages.JSON") < position(longer), "{:?}", results);
+        }
+        assert!(!results.contains(&"pkgs.vim".to_string()));
+    }
+
+    #[test]
+    fn ranks_exact_names_and_top_level_packages_first() {
+        let (_dir, cache) = json_cache();
+        assert_eq!(search(&cache, &["json", "c"], &SearchFilters::default())[0], "pkgs.json-c");
+        assert_eq!(search(&cache, &["jq"], &SearchFilters::default()), ["pkgs.jq", "pkgs.python3Packages.jq"]);
+        assert_eq!(search(&cache, &["jsonschema"], &SearchFilters::default()), ["pkgs.python3Packages.jsonschema"]);
+        // a typo still finds the package
+        assert_eq!(search(&cache, &["jsonpikcle"], &SearchFilters::default()), ["pkgs.python3Packages.jsonpickle"]);
+    }
+
+    #[test]
+    fn filters_results() {
+        let with_meta = |pname: &str, license: &str, unfree: bool, broken: bool| {
+            let mut entry = entry(pname, "JSON tool");
+            entry.meta.licenses = vec![license.to_string()];
+            entry.meta.unfree = unfree;
+            entry.meta.broken = broken;
+            entry
+        };
+        let (_dir, cache) = open_cache(vec![
+            ("jq", with_meta("jq", "MIT", false, false)),
+            ("json-gpl", with_meta("json-gpl", "GPL-3.0-or-later", false, true)),
+            ("json-pro", with_meta("json-pro", "unfree", true, false)),
+            ("python3Packages.jsonschema", with_meta("jsonschema", "MIT", false, false)),
+        ]);
+        let sorted = |filters: SearchFilters| {
+            let mut results = search(&cache, &["json"], &filters);
+            results.sort();
+            results
+        };
+        assert_eq!(sorted(SearchFilters { namespace: Some("python3Packages".to_string()), ..Default::default() }),
+                   ["pkgs.python3Packages.jsonschema"]);
+        // a set name must be followed by a dot
+        assert!(sorted(SearchFilters { namespace: Some("python3".to_string()), ..Default::default() }).is_empty());
+        assert_eq!(sorted(SearchFilters { top_level_only: true, ..Default::default() }),
+                   ["pkgs.jq", "pkgs.json-gpl", "pkgs.json-pro"]);
+        assert_eq!(sorted(SearchFilters { license: Some("gpl".to_string()), ..Default::default() }), ["pkgs.json-gpl"]);
+        assert_eq!(sorted(SearchFilters { no_unfree: true, no_broken: true, ..Default::default() }),
+                   ["pkgs.jq", "pkgs.python3Packages.jsonschema"]);
+        let installed_keys = HashSet::from(["pkgs.jq".to_string()]);
+        let installed = |installed: bool| {
+            let filters = SearchFilters { installed: Some(installed), ..Default::default() };
+            let mut results: Vec<String> = rank(&cache, &parse(&["json"]), &filters, &installed_keys)
+                .into_iter().map(|result| result.full_key).collect();
+            results.sort();
+            results
+        };
+        assert_eq!(installed(true), ["pkgs.jq"]);
+        assert_eq!(installed(false), ["pkgs.json-gpl", "pkgs.json-pro", "pkgs.python3Packages.jsonschema"]);
+    }
+}